@@ -0,0 +1,149 @@
+use std::fs::{create_dir_all, read_to_string, remove_dir_all, remove_file, write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Mutex;
+
+use crate::theme;
+
+/// An input archive and the temporary directory it was extracted to
+struct Extracted {
+    archive: PathBuf,
+    dir: PathBuf,
+    /// --keep-temp: the directory stays, only the tables are saved
+    keep: bool,
+}
+
+/// The archive being compiled, whose tables are saved next to it and whose directory is
+/// removed on the way out
+static EXTRACTED: Mutex<Option<Extracted>> = Mutex::new(None);
+
+/// Tables kept for the sources of an archive, which live next to it between runs since the
+/// directory it's extracted to doesn't
+const TABLES: [&str; 2] = [".compilador_banco", ".compilador_banco.failures"];
+
+/// Stands for the extraction directory in the saved tables, as each run extracts somewhere else
+const ROOT: &str = "{archive}";
+
+/// Runs [`remove_temp`] when dropped
+pub struct TempGuard;
+
+impl Drop for TempGuard {
+    fn drop(&mut self) {
+        remove_temp();
+    }
+}
+
+/// Saves the tables of the archive being compiled next to it and removes the directory it was
+/// extracted to, unless --keep-temp
+pub fn remove_temp() {
+    let extracted = match EXTRACTED.lock() {
+        Ok(mut extracted) => extracted.take(),
+        Err(_) => return,
+    };
+    if let Some(extracted) = extracted {
+        if let Err(e) = keep_tables(&extracted.archive, &extracted.dir) {
+            eprintln!(
+                "{} Could not save the tables of {} ({})",
+                theme::error(),
+                extracted.archive.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+        }
+        if !extracted.keep {
+            let _ = remove_dir_all(extracted.dir);
+        }
+    }
+}
+
+pub fn is_archive(input: &str) -> bool {
+    [".tar.gz", ".tgz", ".tar", ".zip"]
+        .iter()
+        .any(|ext| input.to_lowercase().ends_with(ext))
+}
+
+/// Registers `dir`, which `archive` was extracted to, for [`remove_temp`]; it stays with `keep`
+pub fn track(archive: &Path, dir: &Path, keep: bool) {
+    *EXTRACTED.lock().unwrap() = Some(Extracted {
+        archive: archive.to_path_buf(),
+        dir: dir.to_path_buf(),
+        keep,
+    });
+}
+
+/// Unpacks `archive` into a new temporary directory with `tar` or `unzip`
+pub fn extract(archive: &Path) -> Result<PathBuf, String> {
+    let name = archive
+        .file_name()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or_default();
+    let dir = std::env::temp_dir().join(format!("compilador_banco-{}-{}", process::id(), name));
+    create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+    let mut cmd = if name.to_lowercase().ends_with(".zip") {
+        let mut cmd = Command::new("unzip");
+        cmd.arg("-q").arg(archive).arg("-d").arg(&dir);
+        cmd
+    } else {
+        let mut cmd = Command::new("tar");
+        cmd.arg("-xf").arg(archive).arg("-C").arg(&dir);
+        cmd
+    };
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let status = cmd
+        .status()
+        .map_err(|e| format!("Could not run {} ({})", program, e))?;
+    if !status.success() {
+        let _ = remove_dir_all(&dir);
+        return Err(format!("{} failed ({})", program, status));
+    }
+    Ok(dir)
+}
+
+/// Where `table` of the sources in `archive` is kept: `.bank.tar.gz.compilador_banco` for the
+/// cache of `bank.tar.gz`
+fn table_path(archive: &Path, table: &str) -> PathBuf {
+    let name = archive.file_name().unwrap_or_default().to_string_lossy();
+    archive.with_file_name(format!(".{}{}", name, table))
+}
+
+/// Copies the tables saved next to `archive` into the directory `dir` it was extracted to
+pub fn restore_tables(archive: &Path, dir: &Path) -> std::io::Result<()> {
+    let root = dir.canonicalize()?;
+    for table in TABLES {
+        let contents = match read_to_string(table_path(archive, table)) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        let contents = contents.replace(ROOT, root.to_str().unwrap_or_default());
+        write(dir.join(table), contents)?;
+    }
+    Ok(())
+}
+
+/// Saves the tables of the directory `dir` that `archive` was extracted to next to it, or
+/// removes the saved ones a run removed too (like --clean)
+fn keep_tables(archive: &Path, dir: &Path) -> std::io::Result<()> {
+    let root = dir.canonicalize()?;
+    for table in TABLES {
+        let kept = table_path(archive, table);
+        match read_to_string(dir.join(table)) {
+            Ok(contents) => {
+                let mut contents = contents.replace(root.to_str().unwrap_or_default(), ROOT);
+                // Include scans keep paths as they were found, not canonicalized
+                if dir != root {
+                    contents = contents.replace(dir.to_str().unwrap_or_default(), ROOT);
+                }
+                write(kept, contents)?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                if kept.exists() {
+                    remove_file(kept)?;
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read, read_to_string, write, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use yansi::Paint;
+
+use crate::deps;
+use crate::hash;
+use crate::theme;
+
+/// Directory the sources are under, see [`portable`]
+static ROOT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn set_root(dir: &Path) {
+    *ROOT.lock().unwrap() = Some(dir.to_path_buf());
+}
+
+pub fn root() -> Option<PathBuf> {
+    ROOT.lock().unwrap().clone()
+}
+
+/// `file` as its name goes into hashes: relative to the source directory when it's under it, so
+/// checkouts at different paths agree
+pub fn portable(file: &Path) -> String {
+    let root = root();
+    let file = match &root {
+        Some(root) => file.strip_prefix(root).unwrap_or(file),
+        None => file,
+    };
+    file.to_str().unwrap_or_default().to_owned()
+}
+
+/// What a source looked like the last time it was compiled
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub modified: DateTime<Utc>,
+    /// Fingerprint of the inputs shared by every document (see [`fingerprint`])
+    pub fingerprint: String,
+    /// Hash of the output that was written (see [`file_hash`]), empty in older tables
+    pub output_hash: String,
+    /// Hash of the source itself, to recognize it after a rename; empty in older tables
+    pub source_hash: String,
+    /// When the output was built, for --max-age; `None` in older tables and for files that
+    /// weren't compiled, like --touch ones
+    pub built: Option<DateTime<Utc>>,
+}
+
+/// The modification times table kept in `.compilador_banco`
+#[derive(Debug, Default)]
+pub struct Times {
+    pub entries: HashMap<String, Entry>,
+    /// `#key=value` lines at the top of the table, for data that isn't about a single source
+    pub header: BTreeMap<String, String>,
+}
+
+impl Times {
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+
+    /// Records `entry` for `path`, with a modification time no later than now: one from a
+    /// source dated in the future would otherwise be dropped again by every [`get_times`]
+    pub fn insert(&mut self, path: String, mut entry: Entry) {
+        entry.modified = entry.modified.min(Utc::now());
+        self.entries.insert(path, entry);
+    }
+
+    /// Drops the entries of sources that no longer exist, returning their paths
+    pub fn remove_deleted(&mut self) -> Vec<String> {
+        let mut deleted = self
+            .entries
+            .keys()
+            .filter(|path| !Path::new(path).exists())
+            .cloned()
+            .collect::<Vec<_>>();
+        deleted.sort();
+        for path in &deleted {
+            self.entries.remove(path);
+        }
+        deleted
+    }
+
+    /// When the last run that compiled everything without failures started
+    pub fn last_run(&self) -> Option<DateTime<Utc>> {
+        self.header.get("last_run").map(|time| parse_time(time))
+    }
+
+    pub fn set_last_run(&mut self, time: DateTime<Utc>) {
+        self.header.insert("last_run".to_owned(), format_time(time));
+    }
+
+    /// Rolling average of how long compiling a single file takes
+    pub fn average_duration(&self) -> Option<Duration> {
+        let millis = self.header.get("average_ms")?.parse().ok()?;
+        Some(Duration::from_millis(millis))
+    }
+
+    pub fn record_duration(&mut self, duration: Duration) {
+        // Exponential moving average, so the estimate follows changes in the bank or machine
+        let average = match self.average_duration() {
+            Some(average) => average.mul_f64(0.8) + duration.mul_f64(0.2),
+            None => duration,
+        };
+        self.header
+            .insert("average_ms".to_owned(), average.as_millis().to_string());
+    }
+
+    /// Rolling average of how many bytes of output each byte of source makes
+    pub fn size_ratio(&self) -> Option<f64> {
+        self.header.get("size_ratio")?.parse().ok()
+    }
+
+    pub fn record_size(&mut self, source: u64, output: u64) {
+        if source == 0 {
+            return;
+        }
+        let ratio = output as f64 / source as f64;
+        let average = match self.size_ratio() {
+            Some(average) => average * 0.8 + ratio * 0.2,
+            None => ratio,
+        };
+        self.header
+            .insert("size_ratio".to_owned(), format!("{:.4}", average));
+    }
+}
+
+/// Loads the times table: optional `#key=value` header lines, then one
+/// `path;modified[;fingerprint[;output_hash[;source_hash[;built]]]]` line per source, and one
+/// `>path;modified[;cite][;include...]` line per file whose includes were scanned, with `cite`
+/// when it cites something
+pub fn get_times(dir: &Path) -> Times {
+    let mut times = Times::default();
+    let map = &mut times.entries;
+
+    let contents = match read_to_string(dir.join(".compilador_banco")) {
+        Ok(res) => res,
+        Err(e) => {
+            println!(
+                "{} {}: {}",
+                theme::warn(),
+                Paint::new("Load modification times table"),
+                e
+            );
+            return times;
+        }
+    };
+
+    let root = dir.canonicalize().unwrap_or(dir.to_path_buf());
+    let (mut outside, mut duplicates, mut future, mut malformed) = (0, 0, 0, 0);
+    let now = Utc::now();
+    for line in contents.lines() {
+        if let Some(header) = line.strip_prefix('#') {
+            if let Some((key, value)) = header.split_once('=') {
+                times.header.insert(key.to_owned(), value.to_owned());
+            }
+            continue;
+        }
+        if let Some(scanned) = line.strip_prefix('>') {
+            let mut fields = scanned.split(';');
+            let path = PathBuf::from(fields.next().unwrap_or_default());
+            let modified = parse_time(fields.next().unwrap_or_default());
+            // Includes are joined to the including file's directory, so never just `cite`
+            let mut fields = fields.peekable();
+            let cites = fields.next_if_eq(&"cite").is_some();
+            let includes = fields.map(PathBuf::from).collect();
+            deps::remember(path, modified.into(), deps::Scan { includes, cites });
+            continue;
+        }
+
+        // Left half written by an interrupted save, say; the file just compiles again
+        let (filename, entry) = match parse_entry(line) {
+            Some(parsed) => parsed,
+            None => {
+                malformed += 1;
+                continue;
+            }
+        };
+        if !Path::new(filename).starts_with(&root) {
+            outside += 1;
+        }
+        // Written by a machine with a skewed clock, it would hide every later edit; dropping it
+        // makes the file recompile
+        if entry.modified > now {
+            future += 1;
+            continue;
+        }
+
+        // Keep the newest of repeated lines for the same path
+        match map.get(filename) {
+            Some(existing) => {
+                duplicates += 1;
+                if entry.modified > existing.modified {
+                    map.insert(filename.to_owned(), entry);
+                }
+            }
+            None => {
+                map.insert(filename.to_owned(), entry);
+            }
+        }
+    }
+
+    // Tables from before the tag was written hashed with FNV-1a
+    let algorithm = hash::algorithm().name();
+    let tagged = times.header.get("hash").map_or("fnv", String::as_str);
+    if tagged != algorithm && !times.entries.is_empty() {
+        println!(
+            "{} The modification times table was hashed with {}, not {}; every file will be recompiled",
+            theme::warn(),
+            tagged,
+            algorithm
+        );
+        times.entries.clear();
+    }
+
+    if malformed > 0 {
+        println!(
+            "{} Skipped {} malformed lines of the modification times table",
+            theme::warn(),
+            malformed
+        );
+    }
+
+    if duplicates > 0 {
+        println!(
+            "{} Dropped {} duplicate entries from the modification times table",
+            theme::warn(),
+            duplicates
+        );
+    }
+
+    if future > 0 {
+        println!(
+            "{} {} entries of the modification times table are in the future (clock skew?); those files will be recompiled",
+            theme::warn(),
+            future
+        );
+    }
+
+    if outside > 0 {
+        println!(
+            "{} {} entries of the modification times table are outside {}; if the directory was moved, the table is stale and those files will be recompiled",
+            theme::warn(),
+            outside,
+            root.to_str().unwrap_or("UNKNOWN")
+        );
+    }
+
+    times
+}
+
+// ! REMEMBER TO USE .canonicalize on all files before sending to save and also when comparing
+pub fn save_times(dir: &Path, mut times: Times) -> std::io::Result<()> {
+    times
+        .header
+        .insert("hash".to_owned(), hash::algorithm().name().to_owned());
+    let mut saves_file = File::create(dir.join(".compilador_banco"))?;
+    for (key, value) in &times.header {
+        if let Err(e) = writeln!(saves_file, "#{}={}", key, value) {
+            eprintln!(
+                "{} Failed to write to saves file {} ({})",
+                theme::error(),
+                key,
+                e
+            );
+        }
+    }
+    for (filename, entry) in times.entries {
+        if let Err(e) = writeln!(saves_file, "{}", entry_line(&filename, &entry)) {
+            eprintln!(
+                "{} Failed to write to saves file time for {} ({})",
+                theme::error(),
+                filename,
+                e
+            );
+        };
+    }
+    for (path, modified, scan) in deps::remembered() {
+        let mut line = format!(
+            ">{};{}",
+            path.to_str().unwrap_or_default(),
+            format_time(modified.into())
+        );
+        if scan.cites {
+            line += ";cite";
+        }
+        for include in scan.includes {
+            line = format!("{};{}", line, include.to_str().unwrap_or_default());
+        }
+        if let Err(e) = writeln!(saves_file, "{}", line) {
+            eprintln!(
+                "{} Failed to write to saves file includes of {} ({})",
+                theme::error(),
+                path.to_str().unwrap_or("UNKNOWN"),
+                e
+            );
+        }
+    }
+    Ok(())
+}
+
+fn entry_line(filename: &str, entry: &Entry) -> String {
+    let mut line = format!("{};{}", filename, format_time(entry.modified));
+    let built = entry.built.map(format_time).unwrap_or_default();
+    let fields = [
+        &entry.fingerprint,
+        &entry.output_hash,
+        &entry.source_hash,
+        &built,
+    ];
+    // Trailing empty fields are left out, earlier ones keep their place
+    let used = fields.iter().rposition(|field| !field.is_empty());
+    for field in &fields[..used.map_or(0, |last| last + 1)] {
+        line = format!("{};{}", line, field);
+    }
+    line
+}
+
+fn parse_entry(line: &str) -> Option<(&str, Entry)> {
+    let (filename, rest) = line.split_once(';')?;
+    let mut fields = rest.split(';');
+    let time = fields.next().unwrap_or_default();
+    let entry = Entry {
+        modified: parse_time(time),
+        fingerprint: fields.next().unwrap_or_default().to_owned(),
+        output_hash: fields.next().unwrap_or_default().to_owned(),
+        source_hash: fields.next().unwrap_or_default().to_owned(),
+        built: fields
+            .next()
+            .filter(|time| !time.is_empty())
+            .map(parse_time),
+    };
+    Some((filename, entry))
+}
+
+/// Version of the `--export-cache` format, to be bumped when its lines change meaning
+const EXPORT_SCHEMA: &str = "1";
+
+/// Writes the entries of the sources under `root` to `file`, with paths relative to `root` so
+/// another checkout can import them, returning how many there were
+pub fn export(times: &Times, root: &Path, file: &Path) -> std::io::Result<usize> {
+    let mut entries = times
+        .entries
+        .iter()
+        .filter_map(|(filename, entry)| {
+            let relative = Path::new(filename).strip_prefix(root).ok()?;
+            Some(entry_line(&relative.to_str()?.replace('\\', "/"), entry))
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let mut contents = format!(
+        "#schema={}\n#hash={}\n",
+        EXPORT_SCHEMA,
+        hash::algorithm().name()
+    );
+    for line in &entries {
+        contents += line;
+        contents.push('\n');
+    }
+    write(file, contents)?;
+    Ok(entries.len())
+}
+
+/// Reads a file written by [`export`], resolving its paths against `root`
+pub fn import(file: &Path, root: &Path) -> Result<HashMap<String, Entry>, String> {
+    let contents = read_to_string(file).map_err(|e| e.to_string())?;
+    let mut lines = contents.lines();
+    match lines.next().and_then(|line| line.strip_prefix("#schema=")) {
+        Some(EXPORT_SCHEMA) => {}
+        Some(schema) => {
+            return Err(format!(
+                "unsupported schema version {} (expected {})",
+                schema, EXPORT_SCHEMA
+            ))
+        }
+        None => return Err("not an exported cache, the #schema line is missing".to_owned()),
+    }
+
+    let mut entries = HashMap::new();
+    let mut algorithm = "fnv";
+    for line in lines {
+        if let Some(tag) = line.strip_prefix("#hash=") {
+            algorithm = tag;
+            continue;
+        }
+        if algorithm != hash::algorithm().name() {
+            return Err(format!(
+                "hashed with {}, not {}; export it again with --hash-algorithm {}",
+                algorithm,
+                hash::algorithm().name(),
+                algorithm
+            ));
+        }
+        let (relative, entry) =
+            parse_entry(line).ok_or_else(|| format!("invalid line `{}`", line))?;
+        let path = root.join(relative);
+        entries.insert(path.to_str().unwrap_or_default().to_owned(), entry);
+    }
+    Ok(entries)
+}
+
+/// Hash of the paths and contents of `files`, or an empty string when there are none so
+/// tables written before fingerprints existed still match
+pub fn fingerprint(files: &[PathBuf]) -> String {
+    if files.is_empty() {
+        return String::new();
+    }
+
+    let mut contents = Vec::new();
+    for file in files {
+        contents.extend(portable(file).as_bytes());
+        contents.extend(read(file).unwrap_or_default());
+    }
+    hash::digest(&contents)
+}
+
+/// `fingerprint` with a setting only some files have folded in, unchanged without one
+pub fn with_setting(fingerprint: &str, setting: Option<&str>) -> String {
+    match setting {
+        Some(setting) => hash::digest(format!("{}{}", fingerprint, setting).as_bytes()),
+        None => fingerprint.to_owned(),
+    }
+}
+
+/// Hash of the contents of `file`, empty if it can't be read
+pub fn file_hash(file: &Path) -> String {
+    match read(file) {
+        Ok(contents) => hash::digest(&contents),
+        Err(_) => String::new(),
+    }
+}
+
+/// Times are stored as `seconds.nanoseconds` since the epoch, so sub-second edits still count
+pub fn format_time(time: DateTime<Utc>) -> String {
+    format!("{}.{:09}", time.timestamp(), time.timestamp_subsec_nanos())
+}
+
+fn parse_time(time: &str) -> DateTime<Utc> {
+    let (secs, nanos) = time.split_once('.').unwrap_or((time, "0"));
+    Utc.timestamp(secs.parse().unwrap_or(0), nanos.parse().unwrap_or(0))
+}
+
+/// When `path` was last modified
+pub fn mtime(path: &Path) -> DateTime<Utc> {
+    DateTime::<Utc>::from(path.metadata().unwrap().modified().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_round_trip_with_nanos() {
+        for time in [
+            Utc.timestamp(0, 0),
+            Utc.timestamp(1_700_000_000, 1),
+            Utc.timestamp(1_700_000_000, 123_456_789),
+            Utc.timestamp(1_700_000_000, 999_999_999),
+        ] {
+            assert_eq!(parse_time(&format_time(time)), time);
+        }
+        assert_eq!(format_time(Utc.timestamp(12, 5)), "12.000000005");
+    }
+
+    #[test]
+    fn future_entries_are_dropped() {
+        let dir =
+            std::env::temp_dir().join(format!("compilador_banco-future-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let future = format_time(Utc::now() + chrono::Duration::days(365));
+        write(
+            dir.join(".compilador_banco"),
+            format!(
+                "#hash={}\n/bank/past.tex;1700000000.000000000\n/bank/future.tex;{}\n",
+                hash::algorithm().name(),
+                future
+            ),
+        )
+        .unwrap();
+
+        let mut times = get_times(&dir);
+        assert!(times.get("/bank/past.tex").is_some());
+        assert!(times.get("/bank/future.tex").is_none());
+
+        // Recorded from a source dated in the future, it's kept to now instead
+        let entry = times.get("/bank/past.tex").unwrap().clone();
+        let modified = Utc::now() + chrono::Duration::days(365);
+        times.insert("/bank/future.tex".to_owned(), Entry { modified, ..entry });
+        assert!(times.get("/bank/future.tex").unwrap().modified <= Utc::now());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let dir =
+            std::env::temp_dir().join(format!("compilador_banco-truncated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        write(
+            dir.join(".compilador_banco"),
+            format!(
+                "#hash={}\n/bank/a.tex;1700000000.000000000\n/bank/b.t",
+                hash::algorithm().name()
+            ),
+        )
+        .unwrap();
+
+        let times = get_times(&dir);
+        assert_eq!(times.entries.len(), 1);
+        assert!(times.get("/bank/a.tex").is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn duplicate_entries_collapse_to_the_newest() {
+        let dir = std::env::temp_dir().join(format!(
+            "compilador_banco-duplicates-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        write(
+            dir.join(".compilador_banco"),
+            format!(
+                "#hash={}\n\
+                 /bank/a.tex;1700000000.000000000;old\n\
+                 /bank/b.tex;1700000000.000000000\n\
+                 /bank/a.tex;1700000500.000000000;newest\n\
+                 /bank/a.tex;1700000100.000000000;older\n",
+                hash::algorithm().name()
+            ),
+        )
+        .unwrap();
+
+        let times = get_times(&dir);
+        assert_eq!(times.entries.len(), 2);
+        let entry = times.get("/bank/a.tex").unwrap();
+        assert_eq!(entry.modified, Utc.timestamp(1_700_000_500, 0));
+        assert_eq!(entry.fingerprint, "newest");
+
+        save_times(&dir, times).unwrap();
+        let saved = read_to_string(dir.join(".compilador_banco")).unwrap();
+        let lines = saved
+            .lines()
+            .filter(|line| line.starts_with("/bank/a.tex;"))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, ["/bank/a.tex;1700000500.000000000;newest"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
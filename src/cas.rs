@@ -0,0 +1,47 @@
+use std::fs::{copy, create_dir_all, read, rename, write};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+
+use crate::cache;
+use crate::hash;
+
+/// Name of the `--cas-dir` entry for an output built from the inputs described by `parts`
+/// (the [`output_stamp`](crate::output_stamp), format and pandoc arguments), with paths under
+/// the source directory made relative so other checkouts find it
+pub fn key(parts: &[&str]) -> String {
+    let mut text = parts.join("\0");
+    if let Some(root) = cache::root() {
+        let root = format!("{}{}", root.to_str().unwrap_or_default(), MAIN_SEPARATOR);
+        text = text.replace(&root, "");
+    }
+    hash::digest(text.as_bytes())
+}
+
+/// Where the output stored under `key` lives in `dir`, fanned out by its first two characters
+fn entry(dir: &Path, key: &str) -> PathBuf {
+    dir.join(&key[..2.min(key.len())]).join(key)
+}
+
+/// Copies the output stored under `key` to `output`, unless it already has the same contents,
+/// returning whether it changed; `None` when nothing is stored under `key`
+pub fn fetch(dir: &Path, key: &str, output: &Path) -> std::io::Result<Option<bool>> {
+    let stored = match read(entry(dir, key)) {
+        Ok(stored) => stored,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if read(output).ok().as_deref() == Some(stored.as_slice()) {
+        return Ok(Some(false));
+    }
+    write(output, stored)?;
+    Ok(Some(true))
+}
+
+/// Stores `output` under `key`, through a temporary file so other checkouts sharing `dir` never
+/// see half of it
+pub fn store(dir: &Path, key: &str, output: &Path) -> std::io::Result<()> {
+    let entry = entry(dir, key);
+    create_dir_all(entry.parent().unwrap())?;
+    let temp = entry.with_extension(format!("{}.tmp", std::process::id()));
+    copy(output, &temp)?;
+    rename(&temp, &entry)
+}
@@ -0,0 +1,68 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the configuration file looked up in the input directory
+pub const CONFIG_FILE: &str = "compilador_banco.toml";
+
+/// Settings read from `compilador_banco.toml`; flags given on the command line take precedence.
+/// Unknown keys are errors, so a misspelt one doesn't silently do nothing
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Output format used by files no rule matches
+    pub to: Option<String>,
+    /// Extra arguments passed to pandoc before the `--pandoc-arg` ones
+    pub pandoc_args: Vec<String>,
+    pub rules: Vec<Rule>,
+}
+
+/// Settings for the sources under the paths matching a glob; the first matching rule wins
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub path: String,
+    pub to: Option<String>,
+    /// Math renderer, in place of `--math`
+    pub math: Option<String>,
+}
+
+impl Config {
+    /// Reads the configuration at `file`, or the default one if it doesn't exist
+    pub fn load(file: &Path) -> Result<Config, String> {
+        if !file.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = read_to_string(file).map_err(|e| e.to_string())?;
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    }
+}
+
+/// Per-file settings read from the `.cb` file next to a source, e.g. `problem.tex.cb`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sidecar {
+    /// Extra arguments passed to pandoc after every other one
+    pub pandoc_args: Vec<String>,
+}
+
+impl Sidecar {
+    /// Reads the sidecar of `source`, or the default one if it has none
+    pub fn load(source: &Path) -> Result<Sidecar, String> {
+        let file = sidecar_path(source);
+        if !file.exists() {
+            return Ok(Sidecar::default());
+        }
+
+        let contents = read_to_string(&file).map_err(|e| e.to_string())?;
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    }
+}
+
+pub fn sidecar_path(source: &Path) -> PathBuf {
+    let mut file = source.as_os_str().to_owned();
+    file.push(".cb");
+    PathBuf::from(file)
+}
@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::config::sidecar_path;
+use crate::json;
+use crate::latex;
+use crate::metadata;
+
+/// Files each scanned file includes, whether they exist or not, and whether it cites anything,
+/// with the modification time it had when it was read; kept in `.compilador_banco` so unchanged
+/// files aren't read again
+static INCLUDES: Mutex<BTreeMap<PathBuf, (SystemTime, Scan)>> = Mutex::new(BTreeMap::new());
+
+/// What reading a file for its includes found
+#[derive(Debug, Clone, Default)]
+pub struct Scan {
+    pub includes: Vec<PathBuf>,
+    pub cites: bool,
+}
+
+/// Adds a scan made by an earlier run, see [`remembered`]
+pub fn remember(path: PathBuf, modified: SystemTime, scan: Scan) {
+    INCLUDES.lock().unwrap().insert(path, (modified, scan));
+}
+
+/// The scans of the files that still exist
+pub fn remembered() -> Vec<(PathBuf, SystemTime, Scan)> {
+    INCLUDES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(path, _)| path.is_file())
+        .map(|(path, (modified, scan))| (path.clone(), *modified, scan.clone()))
+        .collect()
+}
+
+/// The scan of `path` as it is now, read again only if it changed since the last one
+fn scanned(path: &Path) -> Scan {
+    let modified = match path.metadata().and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return Scan::default(),
+    };
+    let cached = INCLUDES
+        .lock()
+        .unwrap()
+        .get(path)
+        .filter(|(scanned, _)| *scanned == modified)
+        .map(|(_, scan)| scan.clone());
+    match cached {
+        Some(scan) => scan,
+        None => {
+            let scan = scan(path);
+            remember(path.to_path_buf(), modified, scan.clone());
+            scan
+        }
+    }
+}
+
+/// Existing files `path` includes directly, resolved from its directory like LaTeX does
+pub fn direct(path: &Path) -> Vec<PathBuf> {
+    scanned(path)
+        .includes
+        .into_iter()
+        .filter(|file| file.is_file())
+        .collect()
+}
+
+/// `path` with symlinks and `..` resolved, so `./a.tex` and `sub/../a.tex` compare equal; as
+/// given if it doesn't exist
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Whether `path` or any file it includes, recursively, cites something; the documents a
+/// `--bibliography` change rebuilds
+pub fn cites(path: &Path) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![path.to_path_buf()];
+    while let Some(file) = pending.pop() {
+        if !seen.insert(canonical(&file)) {
+            continue;
+        }
+        let scan = scanned(&file);
+        if scan.cites {
+            return true;
+        }
+        pending.extend(scan.includes.into_iter().filter(|file| file.is_file()));
+    }
+    false
+}
+
+/// Every file `path` names in an include command, existing or not, and whether it cites
+fn scan(path: &Path) -> Scan {
+    let source = match read_to_string(path) {
+        Ok(source) => source,
+        Err(_) => return Scan::default(),
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let includes = latex::includes(&source)
+        .into_iter()
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let file = dir.join(name);
+            match file.extension() {
+                Some(_) => file,
+                None => file.with_extension("tex"),
+            }
+        })
+        .collect();
+    Scan {
+        includes,
+        cites: latex::cites(&source),
+    }
+}
+
+/// Every file `path` depends on: its `.cb` and metadata sidecars, and its includes, recursively
+pub fn transitive(path: &Path) -> BTreeSet<PathBuf> {
+    let mut seen = BTreeSet::new();
+    let sidecar = sidecar_path(path);
+    if sidecar.is_file() {
+        seen.insert(sidecar);
+    }
+    seen.extend(metadata::sidecar(path));
+    // Walked by canonical path, as includes reached through `..` would otherwise never repeat
+    let mut visited = BTreeSet::from([canonical(path)]);
+    let mut pending = direct(path);
+    while let Some(file) = pending.pop() {
+        if visited.insert(canonical(&file)) {
+            pending.extend(direct(&file));
+            seen.insert(file);
+        }
+    }
+    seen
+}
+
+/// The first chain of includes leading from `path` back to a file already in the chain, ending
+/// with that file again, if there is one
+pub fn cycle(path: &Path) -> Option<Vec<PathBuf>> {
+    fn visit(
+        file: PathBuf,
+        chain: &mut Vec<PathBuf>,
+        done: &mut BTreeSet<PathBuf>,
+    ) -> Option<Vec<PathBuf>> {
+        let key = canonical(&file);
+        let same = |other: &PathBuf| canonical(other) == key;
+        if let Some(start) = chain.iter().position(same) {
+            let mut cycle = chain[start..].to_vec();
+            cycle.push(file);
+            return Some(cycle);
+        }
+        if !done.insert(key) {
+            return None;
+        }
+        chain.push(file.clone());
+        for include in direct(&file) {
+            if let Some(cycle) = visit(include, chain, done) {
+                return Some(cycle);
+            }
+        }
+        chain.pop();
+        None
+    }
+    visit(path.to_path_buf(), &mut Vec::new(), &mut BTreeSet::new())
+}
+
+/// For each included file, the documents among `documents` that depend on it
+pub fn dependents(documents: &[PathBuf]) -> HashMap<PathBuf, BTreeSet<PathBuf>> {
+    let mut graph: HashMap<PathBuf, BTreeSet<PathBuf>> = HashMap::new();
+    for document in documents {
+        for file in transitive(document) {
+            graph.entry(file).or_default().insert(document.clone());
+        }
+    }
+    graph
+}
+
+/// Graphviz DOT graph of `documents` (boxes) and the files they depend on, with edges from each
+/// file to what it includes and shared files labelled with how many documents they reach
+pub fn dot(documents: &[PathBuf], base: &Path) -> String {
+    // Canonical, so a file reached through `..` is the same node as where it's reached directly
+    let base = canonical(base);
+    let relative = |path: &Path| {
+        let path = canonical(path);
+        let relative = path.strip_prefix(&base).unwrap_or(&path);
+        relative.to_string_lossy().replace('\\', "/")
+    };
+    let name = |path: &Path| json::string(&relative(path));
+    let mut reach: BTreeMap<String, BTreeSet<PathBuf>> = BTreeMap::new();
+    for (file, documents) in dependents(documents) {
+        reach.entry(relative(&file)).or_default().extend(documents);
+    }
+
+    let mut lines = vec![
+        "digraph dependencies {".to_owned(),
+        "  rankdir=LR;".to_owned(),
+    ];
+    let mut edges = BTreeSet::new();
+    for document in documents {
+        lines.push(format!("  {} [shape=box];", name(document)));
+        let sidecar = sidecar_path(document);
+        for file in metadata::sidecar(document)
+            .into_iter()
+            .chain(Some(sidecar).filter(|file| file.is_file()))
+        {
+            edges.insert((name(document), name(&file)));
+        }
+
+        let mut pending = vec![document.clone()];
+        let mut seen = BTreeSet::new();
+        while let Some(file) = pending.pop() {
+            if !seen.insert(canonical(&file)) {
+                continue;
+            }
+            for include in direct(&file) {
+                edges.insert((name(&file), name(&include)));
+                pending.push(include);
+            }
+        }
+    }
+
+    for (file, documents) in reach {
+        lines.push(format!(
+            "  {} [label={}];",
+            json::string(&file),
+            json::string(&format!("{}\nused by {}", file, documents.len()))
+        ));
+    }
+    for (from, to) in edges {
+        lines.push(format!("  {} -> {};", from, to));
+    }
+    lines.push("}".to_owned());
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn files_including_each_other_are_a_cycle() {
+        let dir =
+            std::env::temp_dir().join(format!("compilador_banco-cycle-{}", std::process::id()));
+        create_dir_all(dir.join("sub")).unwrap();
+        let (a, b) = (dir.join("a.tex"), dir.join("sub").join("b.tex"));
+        write(&a, "\\input{sub/b}\n").unwrap();
+        // Through `..`, so it only matches once canonicalized
+        write(&b, "Text\n\\input{../a.tex}\n").unwrap();
+        write(dir.join("c.tex"), "\\input{sub/b}\n").unwrap();
+        write(dir.join("d.tex"), "\\input{e}\n").unwrap();
+        write(dir.join("e.tex"), "Leaf\n").unwrap();
+
+        let chain = cycle(&a).unwrap();
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0], a);
+        assert_eq!(chain[1], b);
+        assert_eq!(chain[2].canonicalize().unwrap(), a.canonicalize().unwrap());
+        // Leads into the cycle without being part of it
+        assert!(cycle(&dir.join("c.tex")).is_some_and(|chain| chain[0] == b));
+        assert!(cycle(&dir.join("d.tex")).is_none());
+
+        // Every pass through the cycle reaches `a.tex` by a longer `sub/../` path; each real
+        // file must still be visited once
+        assert_eq!(transitive(&a).len(), 1);
+        let reached = transitive(&dir.join("c.tex"))
+            .iter()
+            .map(|file| file.canonicalize().unwrap())
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            reached,
+            BTreeSet::from([a.canonicalize().unwrap(), b.canonicalize().unwrap()])
+        );
+        assert!(!cites(&a));
+        let graph = dot(std::slice::from_ref(&a), &dir);
+        assert_eq!(graph.matches(" -> ").count(), 2);
+        assert!(!graph.contains(".."));
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs::{metadata, read_dir};
+use std::io::{stderr, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use yansi::Paint;
+
+use crate::theme;
+
+use crate::options::Options;
+
+/// Every source file under `base`, sorted, descending at most `--max-depth` directory levels
+pub fn find_tex(base: &Path, opts: &Options) -> Vec<PathBuf> {
+    find_sources(base, opts).0
+}
+
+/// Like [`find_tex`], also returning the files --include and --exclude dropped, with why
+pub fn find_sources(base: &Path, opts: &Options) -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+    if !base.is_dir() {
+        return (vec![], vec![]);
+    };
+
+    let progress = Progress::new(!opts.summary_only && stderr().is_terminal());
+    let mut matches = match opts.jobs() {
+        1 => find_tex_within(base, opts, opts.max_depth, &progress),
+        jobs => find_tex_parallel(base, opts, jobs, &progress),
+    };
+    progress.clear();
+    matches.sort();
+
+    let mut dropped = Vec::new();
+    matches.retain(|file| {
+        let relative = file.strip_prefix(base).unwrap_or(file);
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let reason = match opts
+            .exclude
+            .iter()
+            .find(|(_, glob)| glob.contains(&relative))
+        {
+            Some((pattern, _)) => format!("excluded by `{}`", pattern),
+            None if !opts.include.is_empty()
+                && !opts
+                    .include
+                    .iter()
+                    .any(|(_, glob)| glob.contains(&relative)) =>
+            {
+                "not matched by any --include".to_owned()
+            }
+            None => return true,
+        };
+        dropped.push((file.clone(), reason));
+        false
+    });
+    (matches, dropped)
+}
+
+/// `Scanning... N files found` line on stderr, only shown once a scan has taken a while
+struct Progress {
+    enabled: bool,
+    started: Instant,
+    dirs: AtomicUsize,
+    files: AtomicUsize,
+    shown: AtomicBool,
+}
+
+impl Progress {
+    /// How long a scan runs before the line appears, so small trees don't flash it
+    const DELAY: Duration = Duration::from_millis(500);
+
+    fn new(enabled: bool) -> Progress {
+        Progress {
+            enabled,
+            started: Instant::now(),
+            dirs: AtomicUsize::new(0),
+            files: AtomicUsize::new(0),
+            shown: AtomicBool::new(false),
+        }
+    }
+
+    fn read(&self, files: usize) {
+        let files = self.files.fetch_add(files, Ordering::Relaxed) + files;
+        let dirs = self.dirs.fetch_add(1, Ordering::Relaxed) + 1;
+        // Redrawing on every directory would make the terminal the bottleneck
+        if self.enabled && dirs.is_multiple_of(64) && self.started.elapsed() > Progress::DELAY {
+            self.shown.store(true, Ordering::Relaxed);
+            eprint!(
+                "\r\x1b[KScanning... {} files found ({} directories)",
+                files, dirs
+            );
+            let _ = stderr().flush();
+        }
+    }
+
+    fn clear(&self) {
+        if self.shown.load(Ordering::Relaxed) {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+fn find_tex_within(
+    base: &Path,
+    opts: &Options,
+    max_depth: Option<usize>,
+    progress: &Progress,
+) -> Vec<PathBuf> {
+    let (mut matches, subdirs) = read_sources(base, opts, max_depth);
+    progress.read(matches.len());
+    for (dir, depth) in subdirs {
+        matches.append(&mut find_tex_within(&dir, opts, depth, progress));
+    }
+    matches
+}
+
+/// Same as [`find_tex_within`], but with `jobs` threads taking directories from a shared queue
+fn find_tex_parallel(
+    base: &Path,
+    opts: &Options,
+    jobs: usize,
+    progress: &Progress,
+) -> Vec<PathBuf> {
+    // Directories left to read, and how many are being read right now
+    let queue = Mutex::new((vec![(base.to_path_buf(), opts.max_depth)], 0));
+    let changed = Condvar::new();
+    let matches = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| loop {
+                let (dir, depth) = {
+                    let mut state = queue.lock().unwrap();
+                    loop {
+                        if let Some(next) = state.0.pop() {
+                            state.1 += 1;
+                            break next;
+                        }
+                        if state.1 == 0 {
+                            return;
+                        }
+                        state = changed.wait(state).unwrap();
+                    }
+                };
+
+                let (mut found, subdirs) = read_sources(&dir, opts, depth);
+                progress.read(found.len());
+                matches.lock().unwrap().append(&mut found);
+
+                let mut state = queue.lock().unwrap();
+                state.0.extend(subdirs);
+                state.1 -= 1;
+                changed.notify_all();
+            });
+        }
+    });
+
+    matches.into_inner().unwrap()
+}
+
+/// Sources directly inside `dir`, and the subdirectories still within `max_depth`
+fn read_sources(
+    dir: &Path,
+    opts: &Options,
+    max_depth: Option<usize>,
+) -> (Vec<PathBuf>, Vec<(PathBuf, Option<usize>)>) {
+    let (mut matches, mut subdirs) = (Vec::new(), Vec::new());
+    match read_dir(dir) {
+        Ok(read) => {
+            for item in read {
+                // One unreadable entry shouldn't hide the rest of the directory
+                let item = match item {
+                    Ok(item) => item.path(),
+                    Err(e) => {
+                        warn_unreadable(dir, e);
+                        continue;
+                    }
+                };
+                let metadata = match metadata(&item) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        warn_unreadable(&item, e);
+                        continue;
+                    }
+                };
+                if metadata.is_file() && opts.is_source(&item) {
+                    matches.push(item);
+                } else if metadata.is_dir()
+                    && max_depth.is_none_or(|depth| depth > 1)
+                    && !opts.is_output_dir(&item)
+                {
+                    subdirs.push((item, max_depth.map(|depth| depth - 1)));
+                }
+            }
+        }
+        Err(e) => println!(
+            "{} {} {}: {}",
+            theme::warn(),
+            Paint::new("Could not read directory"),
+            dir.to_str().unwrap_or("UNKNOWN"),
+            e
+        ),
+    }
+
+    (matches, subdirs)
+}
+
+fn warn_unreadable(path: &Path, e: std::io::Error) {
+    println!(
+        "{} {} {}: {}",
+        theme::warn(),
+        Paint::new("Skipping unreadable entry"),
+        path.to_str().unwrap_or("UNKNOWN"),
+        e
+    );
+}
+
+/// Every file under `dir`, recursively
+pub fn find_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    if let Ok(read) = read_dir(dir) {
+        for item in read.filter_map(|item| item.ok()) {
+            let item = item.path();
+            if item.is_dir() {
+                files.append(&mut find_files(&item));
+            } else {
+                files.push(item);
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, remove_dir_all, write};
+
+    #[test]
+    fn parallel_discovery_matches_serial() {
+        let base =
+            std::env::temp_dir().join(format!("compilador_banco-discover-{}", std::process::id()));
+        for i in 0..8 {
+            let dir = base
+                .join(format!("part{}", i))
+                .join("chapters")
+                .join("deep");
+            create_dir_all(&dir).unwrap();
+            for (j, name) in ["a.tex", "b.tex", "notes.txt"].iter().enumerate() {
+                write(dir.join(name), "x").unwrap();
+                write(dir.parent().unwrap().join(format!("{}-{}", j, name)), "x").unwrap();
+            }
+        }
+
+        let opts = Options::default();
+        let progress = Progress::new(false);
+        let mut serial = find_tex_within(&base, &opts, None, &progress);
+        serial.sort();
+        // Enough rounds that the threads interleave differently
+        for _ in 0..20 {
+            let mut parallel = find_tex_parallel(&base, &opts, 4, &progress);
+            parallel.sort();
+            assert_eq!(parallel, serial);
+        }
+        assert_eq!(serial.len(), 8 * 4);
+        remove_dir_all(&base).unwrap();
+    }
+
+    /// A dangling symlink fails `metadata()`, like an entry without permission would
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_entries_are_skipped() {
+        let dir =
+            std::env::temp_dir().join(format!("compilador_banco-entries-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join("a.tex"), "x").unwrap();
+        std::os::unix::fs::symlink(dir.join("missing.tex"), dir.join("broken.tex")).unwrap();
+        create_dir_all(dir.join("sub")).unwrap();
+
+        let (matches, subdirs) = read_sources(&dir, &Options::default(), None);
+        assert_eq!(matches, vec![dir.join("a.tex")]);
+        assert_eq!(subdirs, vec![(dir.join("sub"), None)]);
+        remove_dir_all(&dir).unwrap();
+    }
+}
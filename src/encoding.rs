@@ -0,0 +1,101 @@
+/// Character sets `--output-encoding` can transcode HTML outputs to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Latin1,
+    Windows1252,
+    Ascii,
+}
+
+/// What windows-1252 puts at 0x80-0x9F where latin1 has control characters; `None` are unused
+const WINDOWS_1252: [Option<char>; 32] = [
+    Some('\u{20AC}'),
+    None,
+    Some('\u{201A}'),
+    Some('\u{0192}'),
+    Some('\u{201E}'),
+    Some('\u{2026}'),
+    Some('\u{2020}'),
+    Some('\u{2021}'),
+    Some('\u{02C6}'),
+    Some('\u{2030}'),
+    Some('\u{0160}'),
+    Some('\u{2039}'),
+    Some('\u{0152}'),
+    None,
+    Some('\u{017D}'),
+    None,
+    None,
+    Some('\u{2018}'),
+    Some('\u{2019}'),
+    Some('\u{201C}'),
+    Some('\u{201D}'),
+    Some('\u{2022}'),
+    Some('\u{2013}'),
+    Some('\u{2014}'),
+    Some('\u{02DC}'),
+    Some('\u{2122}'),
+    Some('\u{0161}'),
+    Some('\u{203A}'),
+    Some('\u{0153}'),
+    None,
+    Some('\u{017E}'),
+    Some('\u{0178}'),
+];
+
+impl Encoding {
+    pub fn parse(name: &str) -> Option<Encoding> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Encoding::Utf8),
+            "iso-8859-1" | "latin1" | "latin-1" => Some(Encoding::Latin1),
+            "windows-1252" | "cp1252" => Some(Encoding::Windows1252),
+            "us-ascii" | "ascii" => Some(Encoding::Ascii),
+            _ => None,
+        }
+    }
+
+    /// Label for the HTML `<meta charset>`
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Latin1 => "iso-8859-1",
+            Encoding::Windows1252 => "windows-1252",
+            Encoding::Ascii => "us-ascii",
+        }
+    }
+
+    /// `text` in this encoding, or the first character it has no byte for
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, char> {
+        if self == Encoding::Utf8 {
+            return Ok(text.as_bytes().to_vec());
+        }
+        text.chars()
+            .map(|c| match (self, c as u32) {
+                (_, code) if code < 0x80 => Ok(code as u8),
+                (Encoding::Latin1, code) if code < 0x100 => Ok(code as u8),
+                (Encoding::Windows1252, code) if (0xA0..0x100).contains(&code) => Ok(code as u8),
+                (Encoding::Windows1252, _) => WINDOWS_1252
+                    .iter()
+                    .position(|known| *known == Some(c))
+                    .map(|i| 0x80 + i as u8)
+                    .ok_or(c),
+                _ => Err(c),
+            })
+            .collect()
+    }
+
+    /// `bytes` read back as text; bytes the encoding doesn't use become U+FFFD
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 | Encoding::Ascii => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Latin1 => bytes.iter().map(|byte| *byte as char).collect(),
+            Encoding::Windows1252 => bytes
+                .iter()
+                .map(|byte| match byte {
+                    0x80..=0x9F => WINDOWS_1252[(byte - 0x80) as usize].unwrap_or('\u{FFFD}'),
+                    _ => *byte as char,
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use regex::Regex;
+
+/// Shell-style pattern matched against `/`-separated relative paths: `*` and `?` stay within a
+/// path component, `**` crosses them, and `[...]` and `{a,b}` work as in most shells
+#[derive(Debug, Clone)]
+pub struct Glob {
+    regex: Regex,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Glob, String> {
+        let mut regex = String::from("^");
+        let mut chars = pattern.trim_end_matches('/').chars().peekable();
+        let mut braces = 0;
+
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    if chars.peek() == Some(&'/') {
+                        chars.next();
+                        regex.push_str("(?:.*/)?");
+                    } else {
+                        regex.push_str(".*");
+                    }
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                '[' => {
+                    regex.push('[');
+                    if chars.peek() == Some(&'!') {
+                        chars.next();
+                        regex.push('^');
+                    }
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                        if c == '\\' || c == '[' {
+                            regex.push('\\');
+                        }
+                        regex.push(c);
+                    }
+                    regex.push(']');
+                }
+                '{' => {
+                    braces += 1;
+                    regex.push_str("(?:");
+                }
+                ',' if braces > 0 => regex.push('|'),
+                '}' if braces > 0 => {
+                    braces -= 1;
+                    regex.push(')');
+                }
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+
+        Ok(Glob {
+            regex: Regex::new(&regex)
+                .map_err(|e| format!("Invalid pattern {} ({})", pattern, e))?,
+        })
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        self.regex.is_match(path)
+    }
+
+    /// Whether the pattern matches `path` or one of the directories containing it, so `slides`
+    /// applies to everything inside `slides/`
+    pub fn contains(&self, path: &str) -> bool {
+        let mut prefix = path;
+        loop {
+            if self.is_match(prefix) {
+                return true;
+            }
+            match prefix.rsplit_once('/') {
+                Some((parent, _)) => prefix = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Whether `text` has any of the characters [`Glob::new`] treats specially
+pub fn is_pattern(text: &str) -> bool {
+    text.contains(['*', '?', '[', '{'])
+}
+
+/// Splits `pattern` into its leading directories without special characters, and the rest
+pub fn split_base(pattern: &str) -> (&str, &str) {
+    let special = pattern.find(['*', '?', '[', '{']).unwrap_or(pattern.len());
+    match pattern[..special].rfind('/') {
+        Some(slash) => (&pattern[..slash], &pattern[slash + 1..]),
+        None => ("", pattern),
+    }
+}
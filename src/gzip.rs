@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec;
+
+/// Where the compressed copy of `output` goes, next to it
+pub fn path(output: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.gz", output.to_str().unwrap()))
+}
+
+/// Wraps a raw deflate stream of `data` in a gzip member (RFC 1952)
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, max compression, unknown OS
+    let mut gz = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 2, 255];
+    gz.extend(compress_to_vec(data, 9));
+    gz.extend(&crc32(data).to_le_bytes());
+    gz.extend(&(data.len() as u32).to_le_bytes());
+    gz
+}
+
+/// Contents of the first member of the gzip file `gz`, `None` if it isn't one or is damaged
+pub fn decompress(gz: &[u8]) -> Option<Vec<u8>> {
+    if gz.len() < 18 || gz[..3] != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = gz[3];
+    let mut at = 10;
+    // FEXTRA: a length-prefixed field
+    if flags & 4 != 0 {
+        let length = u16::from_le_bytes([*gz.get(at)?, *gz.get(at + 1)?]) as usize;
+        at += 2 + length;
+    }
+    // FNAME and FCOMMENT: zero-terminated strings
+    for flag in [8, 16] {
+        if flags & flag != 0 {
+            at += gz.get(at..)?.iter().position(|byte| *byte == 0)? + 1;
+        }
+    }
+    // FHCRC: a header checksum
+    if flags & 2 != 0 {
+        at += 2;
+    }
+    let data = decompress_to_vec(gz.get(at..gz.len() - 8)?).ok()?;
+    let trailer = &gz[gz.len() - 8..];
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    (crc == crc32(&data)).then_some(data)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_reverses_compress() {
+        let html = b"<p>Hola</p>\n".repeat(100);
+        assert_eq!(decompress(&compress(&html)), Some(html));
+        assert_eq!(decompress(b"<p>not gzip</p>"), None);
+    }
+}
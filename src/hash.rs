@@ -0,0 +1,444 @@
+use std::convert::TryInto;
+use std::sync::Mutex;
+
+/// Digest the cache's fingerprints and output hashes are made with, see `--hash-algorithm`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Algorithm {
+    Blake3,
+    Sha256,
+    #[default]
+    Xxhash,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        match name {
+            "blake3" => Some(Algorithm::Blake3),
+            "sha256" => Some(Algorithm::Sha256),
+            "xxhash" => Some(Algorithm::Xxhash),
+            _ => None,
+        }
+    }
+
+    /// Tag stored in the `#hash` line of the cache header
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Xxhash => "xxhash",
+        }
+    }
+}
+
+static ALGORITHM: Mutex<Algorithm> = Mutex::new(Algorithm::Xxhash);
+
+/// Picks the algorithm [`digest`] uses for the rest of the run
+pub fn set(algorithm: Algorithm) {
+    *ALGORITHM.lock().unwrap() = algorithm;
+}
+
+pub fn algorithm() -> Algorithm {
+    *ALGORITHM.lock().unwrap()
+}
+
+/// Lowercase hex digest of `data` with the algorithm in use
+pub fn digest(data: &[u8]) -> String {
+    let bytes = match algorithm() {
+        Algorithm::Blake3 => blake3(data).to_vec(),
+        Algorithm::Sha256 => sha256(data).to_vec(),
+        Algorithm::Xxhash => xxh64(data, 0).to_be_bytes().to_vec(),
+    };
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a_2f98,
+    0x7137_4491,
+    0xb5c0_fbcf,
+    0xe9b5_dba5,
+    0x3956_c25b,
+    0x59f1_11f1,
+    0x923f_82a4,
+    0xab1c_5ed5,
+    0xd807_aa98,
+    0x1283_5b01,
+    0x2431_85be,
+    0x550c_7dc3,
+    0x72be_5d74,
+    0x80de_b1fe,
+    0x9bdc_06a7,
+    0xc19b_f174,
+    0xe49b_69c1,
+    0xefbe_4786,
+    0x0fc1_9dc6,
+    0x240c_a1cc,
+    0x2de9_2c6f,
+    0x4a74_84aa,
+    0x5cb0_a9dc,
+    0x76f9_88da,
+    0x983e_5152,
+    0xa831_c66d,
+    0xb003_27c8,
+    0xbf59_7fc7,
+    0xc6e0_0bf3,
+    0xd5a7_9147,
+    0x06ca_6351,
+    0x1429_2967,
+    0x27b7_0a85,
+    0x2e1b_2138,
+    0x4d2c_6dfc,
+    0x5338_0d13,
+    0x650a_7354,
+    0x766a_0abb,
+    0x81c2_c92e,
+    0x9272_2c85,
+    0xa2bf_e8a1,
+    0xa81a_664b,
+    0xc24b_8b70,
+    0xc76c_51a3,
+    0xd192_e819,
+    0xd699_0624,
+    0xf40e_3585,
+    0x106a_a070,
+    0x19a4_c116,
+    0x1e37_6c08,
+    0x2748_774c,
+    0x34b0_bcb5,
+    0x391c_0cb3,
+    0x4ed8_aa4a,
+    0x5b9c_ca4f,
+    0x682e_6ff3,
+    0x748f_82ee,
+    0x78a5_636f,
+    0x84c8_7814,
+    0x8cc7_0208,
+    0x90be_fffa,
+    0xa450_6ceb,
+    0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+/// Initial hash values of SHA-256, which BLAKE3 starts from too
+const IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+/// SHA-256 (FIPS 180-4)
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend(&(data.len() as u64 * 8).to_be_bytes());
+
+    let mut state = IV;
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (bytes, word) in digest.chunks_mut(4).zip(state) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+const XXH_PRIME_1: u64 = 0x9e37_79b1_85eb_ca87;
+const XXH_PRIME_2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const XXH_PRIME_3: u64 = 0x1656_67b1_9e37_79f9;
+const XXH_PRIME_4: u64 = 0x85eb_ca77_c2b2_ae63;
+const XXH_PRIME_5: u64 = 0x27d4_eb2f_1656_67c5;
+
+/// XXH64
+fn xxh64(data: &[u8], seed: u64) -> u64 {
+    fn round(acc: u64, input: u64) -> u64 {
+        acc.wrapping_add(input.wrapping_mul(XXH_PRIME_2))
+            .rotate_left(31)
+            .wrapping_mul(XXH_PRIME_1)
+    }
+    fn merge(acc: u64, value: u64) -> u64 {
+        (acc ^ round(0, value))
+            .wrapping_mul(XXH_PRIME_1)
+            .wrapping_add(XXH_PRIME_4)
+    }
+    let u64_at = |bytes: &[u8]| u64::from_le_bytes(bytes[..8].try_into().unwrap());
+
+    let stripes = data.chunks_exact(32);
+    let mut rest = stripes.remainder();
+    let mut hash = if data.len() >= 32 {
+        let mut v = [
+            seed.wrapping_add(XXH_PRIME_1).wrapping_add(XXH_PRIME_2),
+            seed.wrapping_add(XXH_PRIME_2),
+            seed,
+            seed.wrapping_sub(XXH_PRIME_1),
+        ];
+        for stripe in stripes {
+            for (i, lane) in v.iter_mut().enumerate() {
+                *lane = round(*lane, u64_at(&stripe[i * 8..]));
+            }
+        }
+        let hash = v[0]
+            .rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18));
+        v.iter().fold(hash, |hash, lane| merge(hash, *lane))
+    } else {
+        seed.wrapping_add(XXH_PRIME_5)
+    };
+    hash = hash.wrapping_add(data.len() as u64);
+
+    while rest.len() >= 8 {
+        hash ^= round(0, u64_at(rest));
+        hash = hash
+            .rotate_left(27)
+            .wrapping_mul(XXH_PRIME_1)
+            .wrapping_add(XXH_PRIME_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        let word = u32::from_le_bytes(rest[..4].try_into().unwrap());
+        hash ^= u64::from(word).wrapping_mul(XXH_PRIME_1);
+        hash = hash
+            .rotate_left(23)
+            .wrapping_mul(XXH_PRIME_2)
+            .wrapping_add(XXH_PRIME_3);
+        rest = &rest[4..];
+    }
+    for byte in rest {
+        hash ^= u64::from(*byte).wrapping_mul(XXH_PRIME_5);
+        hash = hash.rotate_left(11).wrapping_mul(XXH_PRIME_1);
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(XXH_PRIME_2);
+    hash ^= hash >> 29;
+    hash = hash.wrapping_mul(XXH_PRIME_3);
+    hash ^ (hash >> 32)
+}
+
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+/// BLAKE3 in its default hash mode, with a 32 byte output
+fn blake3(data: &[u8]) -> [u8; 32] {
+    let chunks = match data.is_empty() {
+        true => vec![data],
+        false => data.chunks(1024).collect(),
+    };
+    let cv = match chunks.len() {
+        1 => chunk_cv(chunks[0], 0, ROOT),
+        _ => {
+            let split = left_len(chunks.len());
+            let left = subtree_cv(&chunks[..split], 0);
+            let right = subtree_cv(&chunks[split..], split as u64);
+            parent_cv(left, right, ROOT)
+        }
+    };
+
+    let mut digest = [0u8; 32];
+    for (bytes, word) in digest.chunks_mut(4).zip(cv) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// Chunks in the left subtree of a tree over `chunks` chunks: the largest power of two below it
+fn left_len(chunks: usize) -> usize {
+    1 << (usize::BITS - 1 - (chunks - 1).leading_zeros())
+}
+
+fn subtree_cv(chunks: &[&[u8]], counter: u64) -> [u32; 8] {
+    if chunks.len() == 1 {
+        return chunk_cv(chunks[0], counter, 0);
+    }
+    let split = left_len(chunks.len());
+    let left = subtree_cv(&chunks[..split], counter);
+    let right = subtree_cv(&chunks[split..], counter + split as u64);
+    parent_cv(left, right, 0)
+}
+
+fn parent_cv(left: [u32; 8], right: [u32; 8], flags: u32) -> [u32; 8] {
+    let mut block = [0u32; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    first_8(compress(&IV, &block, 0, 64, PARENT | flags))
+}
+
+fn chunk_cv(chunk: &[u8], counter: u64, flags: u32) -> [u32; 8] {
+    let blocks = match chunk.is_empty() {
+        true => vec![chunk],
+        false => chunk.chunks(64).collect(),
+    };
+    let mut cv = IV;
+    for (i, block) in blocks.iter().enumerate() {
+        let mut padded = [0u8; 64];
+        padded[..block.len()].copy_from_slice(block);
+        let mut words = [0u32; 16];
+        for (word, bytes) in words.iter_mut().zip(padded.chunks(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        let mut block_flags = 0;
+        if i == 0 {
+            block_flags |= CHUNK_START;
+        }
+        if i == blocks.len() - 1 {
+            block_flags |= CHUNK_END | flags;
+        }
+        cv = first_8(compress(
+            &cv,
+            &words,
+            counter,
+            block.len() as u32,
+            block_flags,
+        ));
+    }
+    cv
+}
+
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    words[..8].try_into().unwrap()
+}
+
+fn compress(cv: &[u32; 8], block: &[u32; 16], counter: u64, len: u32, flags: u32) -> [u32; 16] {
+    fn g(state: &mut [u32; 16], [a, b, c, d]: [usize; 4], x: u32, y: u32) {
+        state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+        state[d] = (state[d] ^ state[a]).rotate_right(16);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_right(12);
+        state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+        state[d] = (state[d] ^ state[a]).rotate_right(8);
+        state[c] = state[c].wrapping_add(state[d]);
+        state[b] = (state[b] ^ state[c]).rotate_right(7);
+    }
+
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        len,
+        flags,
+    ];
+    let mut m = *block;
+    for round in 0..7 {
+        g(&mut state, [0, 4, 8, 12], m[0], m[1]);
+        g(&mut state, [1, 5, 9, 13], m[2], m[3]);
+        g(&mut state, [2, 6, 10, 14], m[4], m[5]);
+        g(&mut state, [3, 7, 11, 15], m[6], m[7]);
+        g(&mut state, [0, 5, 10, 15], m[8], m[9]);
+        g(&mut state, [1, 6, 11, 12], m[10], m[11]);
+        g(&mut state, [2, 7, 8, 13], m[12], m[13]);
+        g(&mut state, [3, 4, 9, 14], m[14], m[15]);
+        if round < 6 {
+            m = MSG_PERMUTATION.map(|i| m[i]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(
+            hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn xxh64_known_answers() {
+        assert_eq!(xxh64(b"", 0), 0xef46_db37_51d8_e999);
+        assert_eq!(xxh64(b"abc", 0), 0x44bc_2cf5_ad77_0999);
+    }
+
+    #[test]
+    fn blake3_known_answers() {
+        assert_eq!(
+            hex(&blake3(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            hex(&blake3(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        // The input of the reference test vectors, long enough to take two chunks
+        let input = (0..1025).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        assert_eq!(
+            hex(&blake3(&input)),
+            "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"
+        );
+    }
+}
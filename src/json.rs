@@ -0,0 +1,41 @@
+/// Quotes and escapes text as a JSON string
+pub fn string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// JSON text of a TOML value; dates become strings
+pub fn from_toml(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(text) => string(text),
+        toml::Value::Integer(number) => number.to_string(),
+        toml::Value::Float(number) if number.is_finite() => number.to_string(),
+        toml::Value::Float(_) => "null".to_owned(),
+        toml::Value::Boolean(boolean) => boolean.to_string(),
+        toml::Value::Datetime(datetime) => string(&datetime.to_string()),
+        toml::Value::Array(items) => {
+            let items = items.iter().map(from_toml).collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        }
+        toml::Value::Table(table) => {
+            let entries = table
+                .iter()
+                .map(|(key, value)| format!("{}: {}", string(key), from_toml(value)))
+                .collect::<Vec<_>>();
+            format!("{{{}}}", entries.join(", "))
+        }
+    }
+}
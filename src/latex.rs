@@ -0,0 +1,496 @@
+use std::ops::AddAssign;
+
+const MATH_ENVIRONMENTS: [&str; 7] = [
+    "equation",
+    "align",
+    "gather",
+    "multline",
+    "eqnarray",
+    "math",
+    "displaymath",
+];
+
+/// Environments whose contents aren't LaTeX, so --lint doesn't look inside them
+const VERBATIM_ENVIRONMENTS: [&str; 5] =
+    ["verbatim", "Verbatim", "lstlisting", "minted", "comment"];
+
+/// Rough content counts of a LaTeX source, found by scanning rather than parsing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stats {
+    pub files: usize,
+    pub sections: usize,
+    pub items: usize,
+    pub math: usize,
+}
+
+impl AddAssign for Stats {
+    fn add_assign(&mut self, other: Stats) {
+        self.files += other.files;
+        self.sections += other.sections;
+        self.items += other.items;
+        self.math += other.math;
+    }
+}
+
+pub fn stats(source: &str) -> Stats {
+    let mut stats = Stats {
+        files: 1,
+        ..Stats::default()
+    };
+    let mut inline_dollars = 0;
+
+    for line in source.lines().map(strip_comment) {
+        stats.sections += count_command(line, "section");
+        stats.items += count_command(line, "item");
+        stats.math += MATH_ENVIRONMENTS
+            .iter()
+            .map(|env| {
+                line.matches(&format!("\\begin{{{}}}", env)).count()
+                    + line.matches(&format!("\\begin{{{}*}}", env)).count()
+            })
+            .sum::<usize>();
+        stats.math += line.matches("\\[").count() + line.matches("\\(").count();
+
+        let (display, inline) = dollars(line);
+        stats.math += display / 2;
+        inline_dollars += inline;
+    }
+    stats.math += inline_dollars / 2;
+
+    stats
+}
+
+/// Unescaped `$$` and single `$` delimiters in a line, so `\$$x$` counts two `$` and no `$$`
+fn dollars(line: &str) -> (usize, usize) {
+    let (mut display, mut inline) = (0, 0);
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                display += 1;
+            }
+            '$' => inline += 1,
+            _ => {}
+        }
+    }
+    (display, inline)
+}
+
+/// Number of times `\name` appears as a whole command (so `\section` doesn't count `\sectionmark`)
+fn count_command(line: &str, name: &str) -> usize {
+    let command = format!("\\{}", name);
+    line.match_indices(&command)
+        .filter(|(i, _)| {
+            !line[i + command.len()..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphabetic())
+        })
+        .count()
+}
+
+/// How many `\section{}`s of `source` pandoc numbers, leaving out the starred ones
+pub fn numbered_sections(source: &str) -> usize {
+    source
+        .lines()
+        .map(strip_comment)
+        .map(|line| line.matches("\\section{").count())
+        .sum()
+}
+
+/// The part of a line before an unescaped `%`
+pub fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '%' if !escaped => return &line[..i],
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+/// Argument of `\title{}`, or of the first `\section{}` when there's no title
+pub fn title(source: &str) -> Option<String> {
+    let source = source
+        .lines()
+        .map(strip_comment)
+        .collect::<Vec<_>>()
+        .join("\n");
+    let argument = |command: &str| {
+        let start = source.find(command)? + command.len();
+        Some((start, braced(&source[start..])?))
+    };
+    let title = argument("\\title{").or_else(|| {
+        // Whichever section comes first, starred or not
+        [argument("\\section{"), argument("\\section*{")]
+            .iter()
+            .flatten()
+            .copied()
+            .min_by_key(|(start, _)| *start)
+    })?;
+    Some(title.1.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+/// Each `\section{}` of `source` as a document of its own, with its title: the preamble up to
+/// `\begin{document}`, the section, and `\end{document}`; text before the first section goes
+/// with it. Empty when there are no sections
+pub fn sections(source: &str) -> Vec<(String, String)> {
+    let lines = source.split_inclusive('\n').collect::<Vec<_>>();
+    let code = |line: &str| strip_comment(line).trim_start().to_owned();
+    let starts = lines
+        .iter()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = code(line);
+            let rest = line
+                .strip_prefix("\\section{")
+                .or_else(|| line.strip_prefix("\\section*{"))?;
+            Some((i, braced(rest).unwrap_or_default().to_owned()))
+        })
+        .collect::<Vec<_>>();
+    let first = match starts.first() {
+        Some((first, _)) => *first,
+        None => return vec![],
+    };
+
+    let header = lines[..first]
+        .iter()
+        .position(|line| code(line).contains("\\begin{document}"))
+        .map_or(0, |begin| begin + 1);
+    let footer = lines
+        .iter()
+        .rposition(|line| code(line).contains("\\end{document}"))
+        .filter(|end| *end > first)
+        .unwrap_or(lines.len());
+
+    let mut sections = Vec::new();
+    for (n, (start, title)) in starts.iter().enumerate() {
+        let start = if n == 0 { header } else { *start };
+        let end = starts.get(n + 1).map_or(footer, |(next, _)| *next);
+        let text = [&lines[..header], &lines[start..end], &lines[footer..]]
+            .concat()
+            .concat();
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        sections.push((title, text));
+    }
+    sections
+}
+
+/// Text up to the `}` closing an already opened brace
+fn braced(text: &str) -> Option<&str> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\\' => {
+                escaped = !escaped;
+                continue;
+            }
+            '{' if !escaped => depth += 1,
+            '}' if !escaped && depth == 0 => return Some(&text[..i]),
+            '}' if !escaped => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    None
+}
+
+/// Names of the files pulled in with `\input{}`, `\include{}` or `\subfile{}`, as written
+pub fn includes(source: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for line in source.lines().map(strip_comment) {
+        for command in ["\\input{", "\\include{", "\\subfile{"] {
+            for (i, _) in line.match_indices(command) {
+                if let Some(name) = braced(&line[i + command.len()..]) {
+                    names.push(name.trim());
+                }
+            }
+        }
+    }
+    names
+}
+
+/// Whether `source` cites anything, with `\cite`, `\autocite`, `\citep`, `\nocite` or any other
+/// command with `cite` in its name
+pub fn cites(source: &str) -> bool {
+    source.lines().map(strip_comment).any(|line| {
+        line.split('\\').skip(1).any(|command| {
+            let name = command
+                .split(|c: char| !c.is_ascii_alphabetic())
+                .next()
+                .unwrap_or_default();
+            name.contains("cite")
+        })
+    })
+}
+
+/// Unbalanced braces, `\begin`s without their `\end` (and the other way around) and math `$` or
+/// `$$` left open at the end of a paragraph in `source`, by line number. A scan for --lint,
+/// not a parse: a brace or `$` only counts when it isn't escaped or commented out
+pub fn lint(source: &str) -> Vec<(usize, String)> {
+    let mut problems = Vec::new();
+    let mut braces = Vec::new();
+    let mut environments: Vec<(String, usize)> = Vec::new();
+    let mut math: Option<(&str, usize)> = None;
+    let mut verbatim: Option<(String, usize)> = None;
+
+    for (i, line) in source.lines().enumerate() {
+        let number = i + 1;
+        // What follows the `\end` of a verbatim environment is scanned as usual
+        let mut after_verbatim = line;
+        if let Some((name, _)) = &verbatim {
+            let end = format!("\\end{{{}}}", name);
+            match line.find(&end) {
+                Some(at) => after_verbatim = &line[at + end.len()..],
+                None => continue,
+            }
+            verbatim = None;
+        }
+        if line.trim().is_empty() {
+            if let Some((open, start)) = math.take() {
+                problems.push((
+                    start,
+                    format!("{} not closed before the paragraph ends", open),
+                ));
+            }
+            continue;
+        }
+
+        let mut rest = strip_comment(after_verbatim);
+        while let Some(at) = rest.find(['\\', '{', '}', '$']) {
+            let c = rest[at..].chars().next().unwrap_or_default();
+            rest = &rest[at + 1..];
+            match c {
+                '\\' => {
+                    let length = rest
+                        .find(|c: char| !c.is_ascii_alphabetic())
+                        .unwrap_or(rest.len());
+                    // An escaped character like `\{` or `\$`, or a control symbol like `\\`
+                    if length == 0 {
+                        let skip = rest.chars().next().map_or(0, char::len_utf8);
+                        rest = &rest[skip..];
+                        continue;
+                    }
+                    let command = &rest[..length];
+                    rest = &rest[length..];
+                    match command {
+                        "begin" | "end" => {
+                            let name = match rest.trim_start().strip_prefix('{') {
+                                Some(argument) => match braced(argument) {
+                                    Some(name) => {
+                                        rest = &argument[name.len() + 1..];
+                                        name.trim()
+                                    }
+                                    None => continue,
+                                },
+                                None => continue,
+                            };
+                            if command == "begin" {
+                                if VERBATIM_ENVIRONMENTS.contains(&name) {
+                                    let end = format!("\\end{{{}}}", name);
+                                    match rest.find(&end) {
+                                        Some(at) => rest = &rest[at + end.len()..],
+                                        None => {
+                                            verbatim = Some((name.to_owned(), number));
+                                            break;
+                                        }
+                                    }
+                                    continue;
+                                }
+                                environments.push((name.to_owned(), number));
+                                continue;
+                            }
+                            match environments.pop() {
+                                Some((open, _)) if open == name => {}
+                                Some((open, start)) => problems.push((
+                                    number,
+                                    format!(
+                                        "\\end{{{}}} closes the \\begin{{{}}} of line {}",
+                                        name, open, start
+                                    ),
+                                )),
+                                None => problems
+                                    .push((number, format!("\\end{{{}}} without a \\begin", name))),
+                            }
+                        }
+                        // `\verb|...|` takes anything up to the next delimiter
+                        "verb" => {
+                            let delimiter = match rest.chars().next() {
+                                Some(delimiter) => delimiter,
+                                None => continue,
+                            };
+                            let verbatim = &rest[delimiter.len_utf8()..];
+                            rest = verbatim
+                                .find(delimiter)
+                                .map_or("", |end| &verbatim[end + delimiter.len_utf8()..]);
+                        }
+                        _ => {}
+                    }
+                }
+                '{' => braces.push(number),
+                '}' => {
+                    if braces.pop().is_none() {
+                        problems.push((number, "} without a matching {".to_owned()));
+                    }
+                }
+                _ => {
+                    let delimiter = match rest.strip_prefix('$') {
+                        Some(after) => {
+                            rest = after;
+                            "$$"
+                        }
+                        None => "$",
+                    };
+                    math = match math {
+                        None => Some((delimiter, number)),
+                        Some((open, _)) if open == delimiter => None,
+                        Some((open, start)) => {
+                            problems.push((
+                                number,
+                                format!("{} closes the {} of line {}", delimiter, open, start),
+                            ));
+                            None
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    if let Some((open, start)) = math {
+        problems.push((start, format!("{} never closed", open)));
+    }
+    for start in braces {
+        problems.push((start, "{ never closed".to_owned()));
+    }
+    for (name, start) in environments {
+        problems.push((start, format!("\\begin{{{}}} never closed", name)));
+    }
+    if let Some((name, start)) = verbatim {
+        problems.push((start, format!("\\begin{{{}}} never closed", name)));
+    }
+    problems.sort_by_key(|(line, _)| *line);
+    problems
+}
+
+/// Value of the first `%% key: value` marker comment in `source`, e.g. `%% lang: es`
+pub fn marker(source: &str, key: &str) -> Option<String> {
+    source.lines().find_map(|line| {
+        let (name, value) = line.trim_start().strip_prefix("%%")?.split_once(':')?;
+        Some(value.trim().to_owned()).filter(|value| name.trim() == key && !value.is_empty())
+    })
+}
+
+/// `title` reduced to lowercase ASCII letters, digits and dashes, for use as a file name
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+    let mut chars = title.chars().peekable();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            // Drop commands like `\emph`, and the backslash of accents like `\'a`
+            '\\' => {
+                if chars.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    while chars.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                        chars.next();
+                    }
+                } else {
+                    chars.next();
+                }
+                continue;
+            }
+            'á' | 'à' | 'ä' | 'â' | 'Á' | 'À' | 'Ä' | 'Â' => 'a',
+            'é' | 'è' | 'ë' | 'ê' | 'É' | 'È' | 'Ë' | 'Ê' => 'e',
+            'í' | 'ì' | 'ï' | 'î' | 'Í' | 'Ì' | 'Ï' | 'Î' => 'i',
+            'ó' | 'ò' | 'ö' | 'ô' | 'Ó' | 'Ò' | 'Ö' | 'Ô' => 'o',
+            'ú' | 'ù' | 'ü' | 'û' | 'Ú' | 'Ù' | 'Ü' | 'Û' => 'u',
+            'ñ' | 'Ñ' => 'n',
+            'ç' | 'Ç' => 'c',
+            c => c.to_ascii_lowercase(),
+        };
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_counts_escaped_dollars_apart() {
+        assert_eq!(stats("Price \\$$x$ \\$$").math, 1);
+        assert_eq!(stats("$a$ and $$b$$ cost \\$5").math, 2);
+    }
+
+    #[test]
+    fn lint_finds_unbalanced_braces() {
+        assert_eq!(
+            lint("\\textbf{a\nb}}\n\\emph{c\n"),
+            [
+                (2, "} without a matching {".to_owned()),
+                (3, "{ never closed".to_owned())
+            ]
+        );
+        // Escaped and commented out braces don't count
+        assert!(lint("\\{ 50\\% % }\n").is_empty());
+    }
+
+    #[test]
+    fn lint_finds_mismatched_environments() {
+        assert_eq!(
+            lint("\\begin{itemize}\n\\item a\n\\end{enumerate}\n"),
+            [(
+                3,
+                "\\end{enumerate} closes the \\begin{itemize} of line 1".to_owned()
+            )]
+        );
+        assert_eq!(
+            lint("\\end{proof}\n"),
+            [(1, "\\end{proof} without a \\begin".to_owned())]
+        );
+    }
+
+    #[test]
+    fn lint_finds_math_open_at_a_blank_line() {
+        assert_eq!(
+            lint("Let $x be\n\nNext $y$\n"),
+            [(1, "$ not closed before the paragraph ends".to_owned())]
+        );
+        assert!(lint("Let $x\n= 1$\n").is_empty());
+    }
+
+    #[test]
+    fn lint_skips_verbatim_text() {
+        assert!(lint("\\verb|{| and \\verb+$+\n").is_empty());
+        assert!(lint("\\begin{verbatim}\n{ $\n\\end{verbatim}\n").is_empty());
+        // Closed on the line it opens, so the lines after it are checked again
+        assert_eq!(
+            lint(
+                "\\begin{verbatim}x\\end{verbatim}\n\
+                 \n\
+                 \\emph{a\n\
+                 \n\
+                 \\begin{itemize}\n\
+                 \\end{enumerate}\n"
+            ),
+            [
+                (3, "{ never closed".to_owned()),
+                (
+                    6,
+                    "\\end{enumerate} closes the \\begin{itemize} of line 5".to_owned()
+                )
+            ]
+        );
+    }
+}
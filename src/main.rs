@@ -1,247 +1,4270 @@
-use std::collections::HashMap;
-use std::env::{args, current_dir};
-use std::fs::{create_dir, create_dir_all, read_dir, read_to_string, File};
-use std::io::Write;
-use std::path::{Path, PathBuf};
-use std::process::{exit, Command};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::env::{args, current_dir, var_os};
+use std::fs::{
+    copy, create_dir_all, read, read_dir, read_to_string, remove_dir, remove_dir_all, remove_file,
+    rename, write, File,
+};
+use std::io::{stdin, stdout, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
 use chrono::prelude::*;
 use human_panic::setup_panic;
+use regex::Regex;
+use serde::Serialize;
 #[cfg(not(target_os = "windows"))]
 use spinners::{Spinner, Spinners};
 use which::which;
 use yansi::Paint;
 
+mod archive;
+mod cache;
+mod cas;
+mod config;
+mod deps;
+mod discover;
+mod encoding;
+mod glob;
+mod gzip;
+mod hash;
+mod json;
+mod latex;
+mod manifest;
+mod metadata;
+mod options;
+mod profile;
+mod rename;
+mod report;
+mod site;
+mod sqlite;
+mod status;
+mod theme;
+mod warnings;
+mod watch;
+
+use cache::{file_hash, format_time, get_times, mtime, Entry, Times};
+use config::{sidecar_path, Config, Rule, Sidecar, CONFIG_FILE};
+use discover::{find_files, find_sources, find_tex};
+use glob::Glob;
+use latex::Stats;
+use manifest::Manifest;
+use options::{expand_env, extension, Options, USAGE};
+use profile::Profile;
+use rename::{OutputNames, RenameMap};
+use report::Report;
+
+// Exit statuses, listed in USAGE
+/// Invalid flags, input or configuration
+const EXIT_USAGE: i32 = 1;
+const EXIT_NO_PANDOC: i32 = 2;
+/// A file or directory couldn't be read or written
+const EXIT_IO: i32 = 3;
+/// At least one file failed to compile, or pandoc warned more than --max-warnings allows
+const EXIT_COMPILE: i32 = 4;
+/// --verify-cache found entries that don't match the files
+const EXIT_INCONSISTENT: i32 = 5;
+
+#[allow(deprecated)] // human-panic still names std::panic::PanicInfo
 fn main() {
     setup_panic!();
+    // Dropped on a panic too, so the extracted archive doesn't outlive it
+    let _temp = archive::TempGuard;
+    run();
+}
+
+/// [`std::process::exit`], removing the extracted archive first
+fn exit(code: i32) -> ! {
+    archive::remove_temp();
+    std::process::exit(code)
+}
+
+fn run() {
+    let mut opts = match Options::parse(args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("{} {}\n\n{}", theme::error(), e, USAGE);
+            exit(EXIT_USAGE);
+        }
+    };
+    hash::set(opts.hash_algorithm);
+    match opts.color.as_deref().unwrap_or("auto") {
+        "always" => Paint::enable(),
+        "never" => Paint::disable(),
+        _ => {
+            if !stdout().is_terminal() || var_os("NO_COLOR").is_some() {
+                Paint::disable();
+            }
+        }
+    }
+    theme::set(opts.theme.as_deref().unwrap_or("default"));
+    #[cfg(not(unix))]
+    if opts.chmod.is_some() || opts.chmod_dirs.is_some() {
+        println!(
+            "{} --chmod and --chmod-dirs have no effect on this platform",
+            theme::warn()
+        );
+    }
+    if opts.help {
+        print!("{}", USAGE);
+        return;
+    }
+    if opts.list_formats {
+        list_formats();
+        return;
+    }
+
+    let cwd = current_dir().unwrap_or(PathBuf::from("/"));
+    // Every relative path given on the command line resolves against --workdir when it's set
+    let cwd = match &opts.workdir {
+        Some(dir) => match cwd.join(dir).canonicalize() {
+            Ok(dir) if dir.is_dir() => dir,
+            _ => {
+                eprintln!(
+                    "{} Working directory {} does not exist",
+                    theme::error(),
+                    dir.to_str().unwrap_or("UNNAMED")
+                );
+                exit(EXIT_USAGE);
+            }
+        },
+        None => cwd,
+    };
+
+    if opts.init {
+        init_bank(&cwd.join(opts.input.as_deref().unwrap_or_default()));
+        return;
+    }
+
+    let rename = match &opts.rename_map {
+        Some(file) => match RenameMap::load(&cwd.join(file)) {
+            Ok(map) => Some(map),
+            Err(e) => {
+                eprintln!(
+                    "{} Invalid rename map {} ({})",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    e,
+                );
+                exit(EXIT_USAGE);
+            }
+        },
+        None => None,
+    };
+
+    let mut input = opts.input.clone().unwrap_or_default();
+    // An archive is compiled like the directory it unpacks to, with the outputs next to it
+    let archive = if archive::is_archive(&input) && cwd.join(&input).is_file() {
+        let archive = cwd.join(&input);
+        match archive::extract(&archive) {
+            Ok(dir) => {
+                if opts.keep_temp {
+                    println!(
+                        "{} Extracted {} into {}",
+                        theme::info(),
+                        input,
+                        dir.to_str().unwrap_or("UNNAMED")
+                    );
+                }
+                archive::track(&archive, &dir, opts.keep_temp);
+                if let Err(e) = archive::restore_tables(&archive, &dir) {
+                    eprintln!(
+                        "{} Could not read the tables of {} ({})",
+                        theme::error(),
+                        input,
+                        e
+                    );
+                    exit(EXIT_IO);
+                }
+                input = dir.to_str().unwrap().to_owned();
+                Some(archive)
+            }
+            Err(e) => {
+                eprintln!("{} Could not extract {} ({})", theme::error(), input, e);
+                exit(EXIT_IO);
+            }
+        }
+    } else {
+        None
+    };
+    resolve_paths(&cwd, &mut opts);
+    let single_file = input == "-" || cwd.join(&input).is_file();
+    // A pattern like `chapters/**/*.tex` compiles exactly the files it matches
+    let matches = if !single_file && glob::is_pattern(&input) && !cwd.join(&input).exists() {
+        match expand_glob(&cwd, &input) {
+            Ok(files) if !files.is_empty() => Some(files),
+            Ok(_) => {
+                eprintln!("{} No files match '{}'", theme::error(), input);
+                exit(EXIT_USAGE);
+            }
+            Err(e) => {
+                eprintln!("{} Invalid pattern '{}' ({})", theme::error(), input, e);
+                exit(EXIT_USAGE);
+            }
+        }
+    } else {
+        None
+    };
+    if opts.preview_one && (!single_file || input == "-") {
+        eprintln!("{} --preview-one needs a single file", theme::error());
+        exit(EXIT_USAGE);
+    } else if opts.stdout && !single_file {
+        eprintln!(
+            "{} --stdout needs a single file (or - for stdin) as input",
+            theme::error()
+        );
+        exit(EXIT_USAGE);
+    } else if input == "-" && !opts.stdout {
+        eprintln!(
+            "{} Reading from stdin (-) requires --stdout",
+            theme::error()
+        );
+        exit(EXIT_USAGE);
+    } else if !single_file && matches.is_none() && !cwd.join(&input).is_dir() {
+        eprintln!(
+            "{} Input directory '{}' does not exist",
+            theme::error(),
+            input
+        );
+        if let Some(suggestion) = suggest_dir(&cwd.join(&input)) {
+            eprintln!("Did you mean '{}'?", suggestion);
+        }
+        exit(EXIT_USAGE);
+    }
+
+    if opts.stats {
+        print_stats(&cwd.join(&input), &opts);
+        return;
+    }
+
+    // A single file is compiled as if it was the only source in its directory
+    let src_dir = if let Some(files) = &matches {
+        common_ancestor(files)
+    } else if single_file {
+        cwd.join(&input).parent().unwrap_or(&cwd).to_path_buf()
+    } else {
+        cwd.join(&input)
+    };
+    cache::set_root(&src_dir);
+    let config_file = match &opts.config {
+        Some(file) => cwd.join(file),
+        None => src_dir.join(CONFIG_FILE),
+    };
+    if opts.config.is_some() && !config_file.is_file() {
+        eprintln!(
+            "{} Configuration file {} does not exist",
+            theme::error(),
+            config_file.to_str().unwrap_or("UNNAMED")
+        );
+        exit(EXIT_USAGE);
+    }
+    if let Err(e) = Config::load(&config_file).and_then(|config| opts.apply_config(config)) {
+        eprintln!(
+            "{} Invalid configuration file {} ({})",
+            theme::error(),
+            config_file.to_str().unwrap_or("UNNAMED"),
+            e
+        );
+        exit(EXIT_USAGE);
+    }
+
+    let manifest = opts.manifest.as_ref().map(|file| {
+        let manifest = Manifest::load(&cwd.join(file)).unwrap_or_else(|e| {
+            eprintln!(
+                "{} Invalid manifest {} ({})",
+                theme::error(),
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_USAGE);
+        });
+        let missing = manifest.missing(&src_dir);
+        if !missing.is_empty() {
+            for source in missing {
+                eprintln!(
+                    "{} Manifest {} lists {}, which doesn't exist in {}",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    source.to_str().unwrap_or("UNNAMED"),
+                    src_dir.to_str().unwrap_or("UNNAMED")
+                );
+            }
+            exit(EXIT_USAGE);
+        }
+        manifest
+    });
+    if let Some(manifest) = &manifest {
+        for (file, format) in &manifest.entries {
+            opts.manifest_sources.push(file.clone());
+            if let Some(format) = format {
+                let relative = file.to_str().unwrap_or_default().replace('\\', "/");
+                opts.manifest_formats.insert(relative, format.clone());
+            }
+        }
+    }
+
+    let output_root = match &archive {
+        Some(archive) => archive.parent().unwrap_or(&cwd),
+        None => &src_dir,
+    };
+    let mut html_dir = output_root.join(opts.output_subdir.as_deref().unwrap_or("html"));
+    opts.output_dir = Some(html_dir.clone());
+    if opts.datestamp {
+        html_dir.push(Local::now().format("%Y-%m-%d").to_string());
+    }
+    opts.extract_media = opts.extract_media.take().map(|media| html_dir.join(media));
+
+    if opts.print_config {
+        print_config(&opts, &src_dir, &html_dir, &config_file);
+        return;
+    }
+
+    // Held until the process exits, so two runs over DIR don't write its tables at once
+    let _lock = lock_dir(&src_dir, opts.wait_for_lock);
+
+    if opts.clean {
+        clean(&src_dir, &html_dir, &opts);
+        return;
+    }
+
+    if let Some(file) = &opts.export_cache {
+        export_cache(&src_dir, &cwd.join(file));
+        return;
+    }
+    if let Some(name) = &opts.snapshot {
+        snapshot_cache(&src_dir, name, false);
+        return;
+    }
+    if let Some(name) = &opts.restore {
+        snapshot_cache(&src_dir, name, true);
+        return;
+    }
+    if opts.verify_cache {
+        verify_cache(&src_dir, &html_dir, rename.as_ref(), &opts);
+        return;
+    }
+    if let Some(file) = &opts.import_cache {
+        import_cache(&src_dir, &cwd.join(file), &opts);
+        return;
+    }
+
+    let discover = || {
+        if matches.is_some() {
+            expand_glob(&cwd, &input).unwrap_or_default()
+        } else if single_file {
+            vec![cwd.join(&input)]
+        } else if let Some(manifest) = &manifest {
+            manifest
+                .entries
+                .iter()
+                .map(|(file, _)| src_dir.join(file))
+                .collect()
+        } else {
+            find_tex(&src_dir, &opts)
+        }
+    };
+    if let Some(file) = &opts.graph {
+        let file = cwd.join(file);
+        match write(&file, deps::dot(&discover(), &src_dir)) {
+            Ok(()) => println!(
+                "{} Wrote the dependency graph to {}",
+                theme::info(),
+                file.to_str().unwrap_or("UNNAMED")
+            ),
+            Err(e) => {
+                eprintln!(
+                    "{} Could not write the dependency graph to {} ({})",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+                exit(EXIT_IO);
+            }
+        }
+        return;
+    }
+    if opts.touch {
+        touch(&discover(), &src_dir, &html_dir, rename.as_ref(), &opts);
+        return;
+    }
+    if opts.lint {
+        lint(&discover(), &cwd);
+        return;
+    }
+
+    let pandoc = match which("pandoc") {
+        Ok(pandoc) => pandoc,
+        Err(e) => {
+            eprintln!(
+                "{} Could not find suitable pandoc installation ({})",
+                theme::error(),
+                e,
+            );
+            exit(EXIT_NO_PANDOC);
+        }
+    };
+    if opts.limits_pandoc() {
+        if let Err(e) = which("prlimit") {
+            eprintln!(
+                "{} --mem-limit and --cpu-limit need prlimit ({})",
+                theme::error(),
+                e,
+            );
+            exit(EXIT_NO_PANDOC);
+        }
+    }
+    if opts.wants_pdf() {
+        if let Err(e) = which(opts.pdf_engine()) {
+            eprintln!(
+                "{} Could not find PDF engine {} ({})",
+                theme::error(),
+                opts.pdf_engine(),
+                e,
+            );
+            exit(EXIT_NO_PANDOC);
+        }
+    }
+
+    if opts.preview_one {
+        preview(&cwd.join(&input), &mut opts);
+        return;
+    }
+
+    if let Some(reference) = &opts.diff_against {
+        let reference = cwd.join(reference);
+        diff_against(
+            &discover(),
+            &reference,
+            &src_dir,
+            &html_dir,
+            rename.as_ref(),
+            &opts,
+        );
+        return;
+    }
+
+    if opts.stdout {
+        // pandoc reads stdin itself when given `-`
+        let path = if input == "-" {
+            PathBuf::from("-")
+        } else {
+            cwd.join(&input)
+        };
+        let format = opts.to.as_deref().unwrap_or("html");
+        let file_args = opts
+            .file_math_flag(Path::new(""))
+            .into_iter()
+            .collect::<Vec<_>>();
+        if let Err(e) = compile(&path, None, format, &file_args, &opts) {
+            eprintln!("{} {}: {}", theme::error(), input, e);
+            exit(EXIT_COMPILE);
+        }
+        return;
+    }
+
+    if !html_dir.exists() {
+        if let Err(e) = create_dir_all(&html_dir) {
+            eprintln!(
+                "{} Error while creating html directory {} ({})",
+                theme::error(),
+                html_dir.to_str().unwrap_or("UNNAMED"),
+                e,
+            );
+            exit(EXIT_IO);
+        }
+    }
+
+    // Fail before compiling anything rather than on the first output or at the cache write
+    if opts.check_permissions {
+        for (dir, what) in [
+            (&html_dir, "outputs"),
+            (&src_dir, "the modification times table"),
+        ] {
+            if let Err(e) = check_writable(dir) {
+                eprintln!(
+                    "{} Cannot write {} to {} ({}); fix its permissions or choose another directory",
+                    theme::error(),
+                    what,
+                    dir.to_str().unwrap_or("UNNAMED"),
+                    e,
+                );
+                exit(EXIT_IO);
+            }
+        }
+    }
+
+    if let Some(socket) = &opts.status_socket {
+        if let Err(e) = status::connect(&cwd.join(socket)) {
+            eprintln!(
+                "{} Could not connect to the status socket {} ({})",
+                theme::error(),
+                socket.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_IO);
+        }
+    }
+
+    if opts.watch_initial_skip {
+        println!("{} Watching for changes (Ctrl-C to stop)", theme::info());
+        let changed = watch::wait_for_changes(discover, opts.watch_poll(), opts.debounce());
+        println!("{} {} files changed\n", theme::info(), changed.len());
+    }
+
+    let mut first_run = true;
+    loop {
+        let mut profile = Profile::default();
+        let phase = Instant::now();
+        let began = phase;
+        let mut times = get_times(&src_dir);
+        // What the table held before this run, for the --verbose diff of it at the end
+        let loaded = opts.verbose.then(|| times.entries.clone());
+        profile.phase("cache read", phase.elapsed());
+        let previous = times.header.get("pandoc_version").cloned();
+        let version = pandoc_version(&pandoc, &mut times);
+        if previous.is_some() && previous != version && !times.entries.is_empty() {
+            println!(
+                "{} {} was replaced by {}; every file will be recompiled",
+                theme::info(),
+                previous.as_deref().unwrap_or_default(),
+                version.as_deref().unwrap_or("an unknown version")
+            );
+        }
+        if opts.verbose {
+            println!(
+                "{} Using {} ({})\n",
+                theme::info(),
+                version.as_deref().unwrap_or("pandoc of unknown version"),
+                pandoc.to_str().unwrap_or("UNNAMED")
+            );
+        }
+        let mut build_report = Report::new(version.clone(), args().skip(1).collect());
+        let fingerprint = shared_fingerprint(&opts, version.as_deref());
+        let offsets = opts
+            .continue_numbering
+            .then(|| number_offsets(&numbered_sources(&src_dir, &opts)));
+        let file_fingerprint =
+            |path: &Path| file_fingerprint(&fingerprint, path, &src_dir, offsets.as_ref(), &opts);
+        let git_changes = match opts.git || opts.changed_since.is_some() {
+            true => match git_changes(&src_dir, opts.changed_since.as_deref()) {
+                Ok(changes) => Some(changes),
+                Err(e) => {
+                    eprintln!(
+                        "{} {} could not ask git for changes ({})",
+                        theme::error(),
+                        if opts.git { "--git" } else { "--changed-since" },
+                        e
+                    );
+                    exit(EXIT_USAGE);
+                }
+            },
+            false => None,
+        };
+        let (mut generated_files, mut generated_bytes) = (0, 0);
+        let (mut compiled, mut skipped) = (0, 0);
+
+        let mut failures = Vec::new();
+        // Sources with an up to date output, for --index
+        let mut documents = Vec::new();
+        let mut written_outputs = Vec::new();
+        // Sources compiled this run and their outputs, for --sqlite
+        let mut stored = Vec::new();
+
+        // Edits made from here on are newer than the last run once this one is recorded
+        let started = Utc::now();
+        let phase = Instant::now();
+        // Only the first pass retries the failures; later --watch passes compile what changed
+        let retrying = opts.retry_failed && first_run;
+        let mut files = if retrying {
+            get_failures(&src_dir)
+        } else {
+            discover()
+        };
+        // Every other n-th of the sorted files, so each shard gets a similar mix of the bank
+        if let Some((shard, shards)) = opts.shard {
+            files.sort();
+            files = files
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| i % shards == shard - 1)
+                .map(|(_, file)| file)
+                .collect();
+        }
+        if let Some(grep) = &opts.grep {
+            files.retain(|file| {
+                read(file).is_ok_and(|contents| grep.is_match(&String::from_utf8_lossy(&contents)))
+            });
+        }
+        if let Some(recent) = opts.recent {
+            sort_newest_first(&mut files);
+            files.truncate(recent);
+        }
+        // Discovery already lists them by name, or in the manifest's order
+        match opts.compile_order.as_deref() {
+            Some("name") => files.sort(),
+            Some("mtime") if opts.recent.is_none() => sort_newest_first(&mut files),
+            Some("size") => files.sort_by_key(|file| file.metadata().map_or(0, |m| m.len())),
+            _ => {}
+        }
+        profile.phase("discovery", phase.elapsed());
+        // Scanned apart from `discover`, which --watch also calls on every poll
+        let filtered = !opts.include.is_empty() || !opts.exclude.is_empty();
+        if opts.verbose && filtered && !single_file && matches.is_none() {
+            for (file, reason) in find_sources(&src_dir, &opts).1 {
+                println!(
+                    "{} ./{}: Skipped, {}",
+                    theme::info(),
+                    file.strip_prefix(&cwd)
+                        .unwrap_or(&file)
+                        .to_str()
+                        .unwrap_or("UNNAMED"),
+                    reason
+                );
+            }
+        }
+        if files.is_empty() && opts.fail_on_empty && !opts.retry_failed {
+            eprintln!(
+                "{} No source files found in {}; check the directory, --ext and --max-depth",
+                theme::error(),
+                src_dir
+                    .strip_prefix(&cwd)
+                    .unwrap_or(&src_dir)
+                    .to_str()
+                    .unwrap_or("UNNAMED")
+            );
+            exit(EXIT_USAGE);
+        }
+        // Numbered by their place among every source, so they don't depend on which files run
+        let names = (opts.name_by_title || opts.number_outputs).then(|| {
+            OutputNames::new(
+                &numbered_sources(&src_dir, &opts),
+                opts.name_by_title,
+                opts.number_outputs,
+            )
+        });
+        for untitled in names.iter().flat_map(|names| &names.untitled) {
+            if files.contains(untitled) && !opts.summary_only {
+                println!(
+                    "{} ./{}: No title found, naming the output after the file",
+                    theme::warn(),
+                    untitled
+                        .strip_prefix(&cwd)
+                        .unwrap_or(untitled)
+                        .to_str()
+                        .unwrap_or("UNNAMED")
+                );
+            }
+        }
+        if let Some(rename) = &rename {
+            let stems = files
+                .iter()
+                .filter_map(|file| file.file_stem().and_then(|stem| stem.to_str()));
+            for pattern in rename.unused(stems) {
+                println!(
+                    "{} Rename map entry `{}` does not match any file",
+                    theme::warn(),
+                    pattern
+                );
+            }
+        }
+
+        let outputs = |path: &Path| {
+            output_path(
+                path,
+                &src_dir,
+                &html_dir,
+                rename.as_ref(),
+                names.as_ref(),
+                &opts,
+            )
+        };
+
+        if opts.output_template.is_some() {
+            check_template_outputs(&files, &cwd, &html_dir, &outputs, &opts);
+        }
+
+        if opts.estimate || opts.estimate_size {
+            let stale = |file: &Path| {
+                staleness(
+                    file,
+                    &src_dir,
+                    &outputs(file),
+                    &times,
+                    &file_fingerprint(file),
+                    git_changes.as_ref(),
+                    retrying,
+                    &opts,
+                )
+                .0
+            };
+            estimate(&files, &html_dir, &times, &stale, &opts);
+            return;
+        }
+
+        // After the read-only modes above, as it moves outputs and their entries
+        let renamed = carry_over_renames(
+            &files,
+            &src_dir,
+            &mut times,
+            &outputs,
+            &file_fingerprint,
+            &opts,
+        );
+        // The row of the old path goes, one for the new path takes its place
+        let mut renamed_from = Vec::new();
+        for (old_key, path, output) in renamed {
+            renamed_from.push(old_key);
+            if opts.sqlite.is_some() {
+                stored.push((path, output));
+            }
+        }
+
+        let phase = Instant::now();
+        let media_before = media_files(&opts);
+        // With --threads-io, pandoc runs for every stale file up front and the loop below
+        // only reports the results
+        let mut prebuilt = match opts.threads_io {
+            1 => HashMap::new(),
+            threads => {
+                let stale = files
+                    .iter()
+                    .map(|file| cwd.join(file))
+                    .filter(|path| path.exists())
+                    .filter_map(|path| {
+                        let output = output_path(
+                            &path,
+                            &src_dir,
+                            &html_dir,
+                            rename.as_ref(),
+                            names.as_ref(),
+                            &opts,
+                        );
+                        let stale = staleness(
+                            &path,
+                            &src_dir,
+                            &output,
+                            &times,
+                            &file_fingerprint(&path),
+                            git_changes.as_ref(),
+                            retrying,
+                            &opts,
+                        )
+                        .0;
+                        let protected = opts.protect_newer_output
+                            && edited_output(&path, &output, &times, &opts);
+                        let file_args = file_args(&path, &src_dir, offsets.as_ref(), &opts);
+                        let stamp = (opts.embed_fingerprint || opts.cas_dir.is_some())
+                            .then(|| output_stamp(&path, &output, &file_fingerprint(&path), &opts));
+                        Some((path, output, file_args, stamp)).filter(|_| stale && !protected)
+                    })
+                    .collect();
+                build_parallel(stale, &src_dir, &opts, threads)
+            }
+        };
+        for (i, file) in files.iter().enumerate() {
+            let path = cwd.join(file);
+            if opts.progress_json {
+                let relative = path.strip_prefix(&cwd).unwrap_or(&path);
+                status::progress(i + 1, files.len(), Some(relative));
+            }
+            // --summary-only leaves nothing but the final line
+            let report = !opts.summary_only;
+            // --compact folds the compiling, spinner and result lines into one
+            let report_steps = report && !opts.compact;
+            if !path.exists() {
+                build_report.file(path.strip_prefix(&cwd).unwrap_or(&path), "missing", None);
+                if !report {
+                    continue;
+                }
+                eprintln!(
+                    "{} ./{}: File does not exist",
+                    theme::error(),
+                    Paint::new(
+                        path.strip_prefix(&cwd) // TODO Strip src_dir instead?
+                            .unwrap_or(&path)
+                            .as_os_str()
+                            .to_str()
+                            .unwrap_or("UNNAMED")
+                    )
+                    .bold(),
+                    // Paint::red("File does not exist")
+                );
+                continue;
+            }
+
+            let format = opts.output_format(path.strip_prefix(&src_dir).unwrap());
+            let output = output_path(
+                &path,
+                &src_dir,
+                &html_dir,
+                rename.as_ref(),
+                names.as_ref(),
+                &opts,
+            );
+            // Sampled before compiling, so edits made while pandoc runs are caught next time
+            let modified = newest_input(&path).0;
+            let (stale, reason) = staleness(
+                &path,
+                &src_dir,
+                &output,
+                &times,
+                &file_fingerprint(&path),
+                git_changes.as_ref(),
+                retrying,
+                &opts,
+            );
+            let (reason, target) = if opts.verbose {
+                let output = output.strip_prefix(&cwd).unwrap_or(&output);
+                (
+                    format!(" ({})", reason),
+                    format!(" into ./{}", output.to_str().unwrap_or("UNNAMED")),
+                )
+            } else {
+                (String::new(), String::new())
+            };
+            if stale && opts.protect_newer_output && edited_output(&path, &output, &times, &opts) {
+                let message = "Output was modified after it was built, not overwriting it";
+                let relative = path.strip_prefix(&cwd).unwrap_or(&path);
+                if opts.strict {
+                    if report {
+                        eprintln!(
+                            "{} ./{}: {}",
+                            theme::error(),
+                            relative.to_str().unwrap_or("UNNAMED"),
+                            message
+                        );
+                    }
+                    build_report.failure(relative, Duration::ZERO, message);
+                    failures.push(path);
+                    continue;
+                }
+                if report {
+                    println!(
+                        "{} ./{}: {}",
+                        theme::warn(),
+                        relative.to_str().unwrap_or("UNNAMED"),
+                        message
+                    );
+                }
+                skipped += 1;
+                build_report.file(relative, "protected", None);
+                documents.push((path, output));
+                continue;
+            }
+            if stale {
+                status::file(
+                    "compiling",
+                    path.strip_prefix(&cwd).unwrap_or(&path),
+                    None,
+                    None,
+                );
+            }
+            if stale && report_steps {
+                println!(
+                    "{} ./{}: Compiling LaTeX to HTML{}{}",
+                    theme::info(),
+                    Paint::new(
+                        path.strip_prefix(&cwd)
+                            .unwrap_or(&path)
+                            .as_os_str()
+                            .to_str()
+                            .unwrap_or("UNNAMED")
+                    )
+                    .bold(),
+                    target,
+                    reason
+                );
+            }
+            if stale {
+                #[cfg(not(target_os = "windows"))]
+                let sp = report_steps
+                    .then(|| Spinner::new(&Spinners::OrangeBluePulse, "Executing pandoc".into()));
+                let (result, elapsed) = match prebuilt.remove(&path) {
+                    Some(done) => done,
+                    None => {
+                        let started = Instant::now();
+                        let file_args = file_args(&path, &src_dir, offsets.as_ref(), &opts);
+                        let stamp = (opts.embed_fingerprint || opts.cas_dir.is_some())
+                            .then(|| output_stamp(&path, &output, &file_fingerprint(&path), &opts));
+                        let result = build_output(
+                            &path,
+                            &output,
+                            format,
+                            &file_args,
+                            stamp.as_deref(),
+                            &opts,
+                        );
+                        (result, started.elapsed())
+                    }
+                };
+                profile.file("compile", path.strip_prefix(&src_dir).unwrap(), elapsed);
+                let written = match result {
+                    Ok(written) => written,
+                    Err(e) => {
+                        #[cfg(not(target_os = "windows"))]
+                        if let Some(sp) = sp {
+                            sp.stop();
+                        }
+                        if report && opts.compact {
+                            eprintln!(
+                                "{} ./{}: {}",
+                                theme::error(),
+                                path.strip_prefix(&cwd)
+                                    .unwrap_or(&path)
+                                    .to_str()
+                                    .unwrap_or("UNNAMED"),
+                                e
+                            );
+                        } else if report {
+                            eprintln!(
+                                "\n{} ./{}: {}",
+                                theme::error(),
+                                Paint::new(
+                                    path.strip_prefix(&cwd)
+                                        .unwrap_or(&path)
+                                        .as_os_str()
+                                        .to_str()
+                                        .unwrap_or("UNNAMED")
+                                )
+                                .bold(),
+                                e
+                            );
+                            println!();
+                        }
+                        build_report.failure(path.strip_prefix(&cwd).unwrap_or(&path), elapsed, &e);
+                        failures.push(path);
+                        if opts.fail_fast {
+                            // Keep what compiled so far before bailing out
+                            save_times(&src_dir, times);
+                            save_failures(&src_dir, &failures);
+                            exit(EXIT_COMPILE);
+                        }
+                        continue;
+                    }
+                };
+                compiled += 1;
+                times.record_duration(elapsed);
+                if opts.sqlite.is_some() {
+                    stored.push((path.clone(), output.clone()));
+                }
+                build_report.file(
+                    path.strip_prefix(&cwd).unwrap_or(&path),
+                    if written { "compiled" } else { "unchanged" },
+                    Some(elapsed),
+                );
+                if written {
+                    let mut produced = vec![output.clone(), gzip::path(&output)];
+                    produced.extend(section_outputs(&path, &output, &opts));
+                    produced.retain(|file| file.exists());
+                    times.record_size(
+                        path.metadata().map_or(0, |metadata| metadata.len()),
+                        produced
+                            .iter()
+                            .filter_map(|file| file.metadata().ok())
+                            .map(|metadata| metadata.len())
+                            .sum(),
+                    );
+                    if opts.chmod.is_some() || opts.chmod_dirs.is_some() {
+                        if let (Err(e), true) = (chmod_outputs(&produced, &html_dir, &opts), report)
+                        {
+                            eprintln!(
+                                "{} ./{}: Could not set output permissions ({})",
+                                theme::warn(),
+                                path.strip_prefix(&cwd)
+                                    .unwrap_or(&path)
+                                    .to_str()
+                                    .unwrap_or("UNNAMED"),
+                                e
+                            );
+                        }
+                    }
+                    for file in produced {
+                        if let Ok(metadata) = file.metadata() {
+                            generated_files += 1;
+                            generated_bytes += metadata.len();
+                            written_outputs.push(file);
+                        }
+                    }
+                    if opts.preserve_times {
+                        if let (Err(e), true) = (copy_mtime(&path, &output), report) {
+                            eprintln!(
+                                "{} ./{}: Could not set output modification time ({})",
+                                theme::warn(),
+                                path.strip_prefix(&cwd)
+                                    .unwrap_or(&path)
+                                    .to_str()
+                                    .unwrap_or("UNNAMED"),
+                                e
+                            );
+                        }
+                    }
+                }
+                #[cfg(not(target_os = "windows"))]
+                if let Some(sp) = sp {
+                    sp.message(if written {
+                        "Successfully compiled \u{2705}\n".to_owned()
+                    } else {
+                        "Compiled, output unchanged \u{2705}\n".to_owned()
+                    });
+                    std::thread::sleep(std::time::Duration::from_millis(90)); // Give time to change message
+                    sp.stop();
+                }
+                if report && opts.compact {
+                    println!(
+                        "{} ./{}: Compiled{}{} in {:.2}s{}",
+                        theme::info(),
+                        path.strip_prefix(&cwd)
+                            .unwrap_or(&path)
+                            .to_str()
+                            .unwrap_or("UNNAMED"),
+                        target,
+                        reason,
+                        elapsed.as_secs_f64(),
+                        if written { "" } else { ", output unchanged" }
+                    );
+                }
+                #[cfg(target_os = "windows")]
+                if report_steps {
+                    println!(
+                        "Successfully compiled {} \u{2705}",
+                        path.strip_prefix(&cwd)
+                            .unwrap_or(&path)
+                            .as_os_str()
+                            .to_str()
+                            .unwrap_or("UNNAMED")
+                    );
+                }
+
+                // Record the source modification time the output corresponds to, and what the
+                // output looked like so damage to it is noticed
+                let built = match opts.gzip_only {
+                    true => gzip::path(&output),
+                    false => output.clone(),
+                };
+                times.insert(
+                    cache_key(&path),
+                    Entry {
+                        modified,
+                        fingerprint: file_fingerprint(&path),
+                        output_hash: file_hash(&built),
+                        source_hash: file_hash(&path),
+                        built: Some(Utc::now()),
+                    },
+                );
+            } else {
+                skipped += 1;
+                build_report.file(path.strip_prefix(&cwd).unwrap_or(&path), "skipped", None);
+                if opts.quiet_skips || !report {
+                    documents.push((path, output));
+                    continue;
+                }
+                println!(
+                    "{} ./{}: No changes since last compilation{}",
+                    theme::info(),
+                    Paint::new(
+                        path.strip_prefix(&cwd)
+                            .unwrap_or(&path)
+                            .as_os_str()
+                            .to_str()
+                            .unwrap_or("UNNAMED")
+                    )
+                    .bold(),
+                    reason
+                );
+            }
+            documents.push((path, output));
+
+            if report_steps {
+                println!();
+            }
+        }
+        if opts.progress_json {
+            status::progress(files.len(), files.len(), None);
+        }
+        // Each section page is a document of its own for the listings
+        if opts.split_sections {
+            documents = documents
+                .into_iter()
+                .flat_map(|(source, output)| {
+                    let mut pages = vec![(source.clone(), output.clone())];
+                    for page in section_outputs(&source, &output, &opts) {
+                        pages.push((source.clone(), page));
+                    }
+                    pages
+                })
+                .collect();
+        }
+        for file in copy_assets(&src_dir, &html_dir, &mut times, &opts) {
+            generated_files += 1;
+            generated_bytes += file.metadata().map_or(0, |metadata| metadata.len());
+            written_outputs.push(file);
+        }
+        // Media pandoc extracted counts as generated too
+        let media_before = media_before.unwrap_or_default();
+        for (file, modified) in media_files(&opts).unwrap_or_default() {
+            if media_before.get(&file) != Some(&modified) {
+                generated_files += 1;
+                generated_bytes += file.metadata().map_or(0, |metadata| metadata.len());
+                written_outputs.push(file);
+            }
+        }
+        profile.phase("compile", phase.elapsed());
+
+        let phase = Instant::now();
+        if failures.is_empty() {
+            times.set_last_run(started);
+        }
+        // Sources deleted or renamed since they were compiled would otherwise stay forever
+        let deleted = times.remove_deleted();
+        if !deleted.is_empty() && !opts.summary_only {
+            println!(
+                "{} Dropped {} deleted sources from the modification times table",
+                theme::info(),
+                deleted.len()
+            );
+        }
+        if let Some(db) = &opts.sqlite {
+            let deleted = [deleted.as_slice(), &renamed_from].concat();
+            if let Err(e) = sqlite::store(&cwd.join(db), &stored, &deleted, &src_dir, &opts) {
+                eprintln!(
+                    "{} Failed to update {} ({})",
+                    theme::error(),
+                    db.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+            }
+        }
+        if let Some(loaded) = &loaded {
+            print_cache_diff(loaded, &times.entries);
+        }
+        save_times(&src_dir, times);
+        save_failures(&src_dir, &failures);
+        profile.phase("cache write", phase.elapsed());
+        if let Some(file) = &opts.profile {
+            if let Err(e) = profile.save(&cwd.join(file)) {
+                eprintln!(
+                    "{} Failed to write profile {} ({})",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+            }
+        }
+
+        if let Some(file) = &opts.report {
+            if let Err(e) = build_report.save(&cwd.join(file), began.elapsed()) {
+                eprintln!(
+                    "{} Failed to write report {} ({})",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+            }
+        }
+
+        update_site(
+            &cwd,
+            &documents,
+            &src_dir,
+            &html_dir,
+            rename.as_ref(),
+            names.as_ref(),
+            &opts,
+        );
+
+        let too_many_warnings = print_summary(
+            &cwd,
+            compiled,
+            skipped,
+            failures.len(),
+            (generated_files, generated_bytes),
+            &opts,
+        );
+        if let Some(target) = &opts.deploy {
+            if too_many_warnings {
+                println!(
+                    "{} Not deploying to {}, too many warnings",
+                    theme::warn(),
+                    target
+                );
+            } else if failures.is_empty() {
+                deploy(&html_dir, target, &opts);
+            } else {
+                println!(
+                    "{} Not deploying to {}, {} files failed to compile",
+                    theme::warn(),
+                    target,
+                    failures.len()
+                );
+            }
+        }
+        if opts.print_outputs {
+            print_outputs(&written_outputs, opts.outputs_format.as_deref());
+        }
+        // Left alone after a failed run, so make still sees its target as out of date
+        let succeeded = failures.is_empty() && !too_many_warnings;
+        if let Some(stamp) = opts.stamp.as_ref().filter(|_| succeeded) {
+            if let Err(e) = touch_stamp(&cwd.join(stamp)) {
+                eprintln!(
+                    "{} Failed to update stamp {} ({})",
+                    theme::error(),
+                    stamp.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+            }
+        }
+
+        if !opts.watch {
+            if !failures.is_empty() || too_many_warnings {
+                exit(EXIT_COMPILE);
+            }
+            return;
+        }
+
+        println!("{} Watching for changes (Ctrl-C to stop)", theme::info());
+        let changed = watch::wait_for_changes(discover, opts.watch_poll(), opts.debounce());
+        // Each document once, however many of the files it includes were saved
+        let graph = deps::dependents(&files);
+        let mut affected = BTreeSet::new();
+        for file in &changed {
+            if files.contains(file) {
+                affected.insert(file);
+            }
+            affected.extend(graph.get(file).into_iter().flatten());
+        }
+        println!(
+            "{} {} files changed, {} documents affected\n",
+            theme::info(),
+            changed.len(),
+            affected.len()
+        );
+        first_run = false;
+    }
+}
+
+/// Prints the warnings pandoc gave and the counts of the pass, returning whether there were more
+/// warnings than --max-warnings allows
+fn print_summary(
+    cwd: &Path,
+    compiled: usize,
+    skipped: usize,
+    failed: usize,
+    generated: (usize, u64),
+    opts: &Options,
+) -> bool {
+    // Taken whether or not they're printed, so --watch passes don't add up each other's
+    let warnings = warnings::take();
+    let warning_count = warnings.len();
+    if !opts.summary_only {
+        warnings::print_summary(cwd, warnings);
+        println!(
+            "{} Generated {} files, {}",
+            theme::info(),
+            generated.0,
+            human_size(generated.1)
+        );
+    }
+
+    status::done(compiled, skipped, failed);
+    let total = compiled + skipped + failed;
+    let mut summary = format!(
+        "compiled {} / skipped {} (cache hit rate {:.0}%)",
+        compiled,
+        skipped,
+        if total == 0 {
+            0.0
+        } else {
+            skipped as f64 * 100.0 / total as f64
+        }
+    );
+    if failed > 0 {
+        summary += &format!(", {} failed", failed);
+    }
+    if !opts.summary_only {
+        println!("{} {}", theme::info(), summary);
+    } else if failed == 0 {
+        println!("{} {}", theme::pass(), summary);
+    } else {
+        println!("{} {}", theme::fail(), summary);
+    }
+    let too_many_warnings = opts.max_warnings.is_some_and(|max| warning_count > max);
+    if too_many_warnings {
+        eprintln!(
+            "{} pandoc printed {} warnings, more than --max-warnings {}",
+            theme::error(),
+            warning_count,
+            opts.max_warnings.unwrap_or_default()
+        );
+    }
+    too_many_warnings
+}
+
+/// Resolves the files and directories options name against `cwd`, exiting if one doesn't exist
+fn resolve_paths(cwd: &Path, opts: &mut Options) {
+    for filter in opts.lua_filters.iter_mut() {
+        match cwd.join(&filter).canonicalize() {
+            // Absolute, since pandoc doesn't run from the current directory
+            Ok(absolute) if absolute.is_file() => *filter = absolute,
+            _ => {
+                eprintln!(
+                    "{} Lua filter {} does not exist",
+                    theme::error(),
+                    filter.to_str().unwrap_or("UNNAMED")
+                );
+                exit(EXIT_USAGE);
+            }
+        }
+    }
+    for file in opts
+        .include_before
+        .iter_mut()
+        .chain(opts.include_after.iter_mut())
+        .chain(opts.prepend.iter_mut())
+        .chain(opts.epub_cover.iter_mut())
+        .chain(opts.epub_metadata.iter_mut())
+        .chain(opts.bibliography.iter_mut())
+    {
+        match cwd.join(&file).canonicalize() {
+            Ok(absolute) if absolute.is_file() => *file = absolute,
+            _ => {
+                eprintln!(
+                    "{} File {} does not exist",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED")
+                );
+                exit(EXIT_USAGE);
+            }
+        }
+    }
+    if let Some(dir) = &mut opts.data_dir {
+        match cwd.join(&dir).canonicalize() {
+            Ok(absolute) if absolute.is_dir() => *dir = absolute,
+            _ => {
+                eprintln!(
+                    "{} Data directory {} does not exist",
+                    theme::error(),
+                    dir.to_str().unwrap_or("UNNAMED")
+                );
+                exit(EXIT_USAGE);
+            }
+        }
+    }
+}
+
+/// Exits if an output `--output-template` names for `files` is outside `html_dir`, and warns
+/// about outputs it names for more than one of them
+fn check_template_outputs(
+    files: &[PathBuf],
+    cwd: &Path,
+    html_dir: &Path,
+    outputs: &dyn Fn(&Path) -> PathBuf,
+    opts: &Options,
+) {
+    // A `..` from the template or a rename would write outside the output directory
+    let escaping = files
+        .iter()
+        .filter(|file| {
+            outputs(file)
+                .strip_prefix(html_dir)
+                .map_or(true, |relative| {
+                    relative
+                        .components()
+                        .any(|component| component == Component::ParentDir)
+                })
+        })
+        .collect::<Vec<_>>();
+    if !escaping.is_empty() {
+        eprintln!(
+            "{} --output-template writes outside {}: {}",
+            theme::error(),
+            html_dir
+                .strip_prefix(cwd)
+                .unwrap_or(html_dir)
+                .to_string_lossy(),
+            escaping
+                .iter()
+                .map(|file| file.strip_prefix(cwd).unwrap_or(file).to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        exit(EXIT_USAGE);
+    }
+
+    // A template can map several sources to one output, the last one compiled would win
+    if opts.summary_only {
+        return;
+    }
+    let mut sources: BTreeMap<PathBuf, Vec<&PathBuf>> = BTreeMap::new();
+    for file in files {
+        sources.entry(outputs(file)).or_default().push(file);
+    }
+    for (output, files) in sources.iter().filter(|(_, files)| files.len() > 1) {
+        println!(
+            "{} {} sources are written to {}: {}",
+            theme::warn(),
+            files.len(),
+            output
+                .strip_prefix(cwd)
+                .unwrap_or(output)
+                .to_str()
+                .unwrap_or("UNNAMED"),
+            files
+                .iter()
+                .map(|file| file.strip_prefix(cwd).unwrap_or(file).to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+}
+
+/// Sets the modification time of `stamp` to now, creating it if it doesn't exist
+fn touch_stamp(stamp: &Path) -> std::io::Result<()> {
+    if let Some(parent) = stamp.parent() {
+        create_dir_all(parent)?;
+    }
+    let file = File::options().create(true).append(true).open(stamp)?;
+    file.set_modified(SystemTime::now())
+}
+
+/// Prints how long compiling the stale `files` would take (--estimate) and how much they would
+/// write (--estimate-size), from the timings and sizes of earlier runs
+fn estimate(
+    files: &[PathBuf],
+    html_dir: &Path,
+    times: &Times,
+    stale: &dyn Fn(&Path) -> bool,
+    opts: &Options,
+) {
+    let stale = files
+        .iter()
+        .filter(|file| file.exists() && stale(file))
+        .collect::<Vec<_>>();
+    if opts.estimate {
+        match times.average_duration() {
+            Some(average) => println!(
+                "{} estimated ~{} for {} files",
+                theme::info(),
+                human_duration(average * stale.len() as u32),
+                stale.len()
+            ),
+            None => println!(
+                "{} {} files to compile, but there is no timing data yet to estimate how long it takes",
+                theme::info(),
+                stale.len()
+            ),
+        }
+    }
+    if opts.estimate_size {
+        estimate_size(&stale, html_dir, times);
+    }
+}
+
+/// Prints roughly how much the outputs of the `stale` sources take, from the output to source
+/// size ratio of earlier runs, warning when that's more than the free space left for `html_dir`
+fn estimate_size(stale: &[&PathBuf], html_dir: &Path, times: &Times) {
+    let ratio = match times.size_ratio() {
+        Some(ratio) => ratio,
+        None => {
+            println!(
+                "{} {} files to compile, but there is no size data yet to estimate their outputs",
+                theme::info(),
+                stale.len()
+            );
+            return;
+        }
+    };
+    let sources: u64 = stale
+        .iter()
+        .filter_map(|file| file.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let estimate = (sources as f64 * ratio) as u64;
+    println!(
+        "{} estimated ~{} of output for {} files",
+        theme::info(),
+        human_size(estimate),
+        stale.len()
+    );
+    match free_space(html_dir) {
+        Some(free) if estimate > free => println!(
+            "{} That's more than the {} free on the volume of {}",
+            theme::warn(),
+            human_size(free),
+            html_dir.to_str().unwrap_or("UNNAMED")
+        ),
+        Some(_) => {}
+        None => println!(
+            "{} Could not tell how much space is free for {}",
+            theme::warn(),
+            html_dir.to_str().unwrap_or("UNNAMED")
+        ),
+    }
+}
+
+/// Bytes available on the volume of `dir`, as `df` reports them
+fn free_space(dir: &Path) -> Option<u64> {
+    let output = Command::new("df").arg("-Pk").arg(dir).output().ok()?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // Filesystem, 1024-blocks, Used, Available, Capacity, Mounted on
+    let kilobytes: u64 = stdout
+        .lines()
+        .nth(1)?
+        .split_whitespace()
+        .nth(3)?
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// Lists `outputs` on stdout for --print-outputs, in the --outputs-format
+fn print_outputs(outputs: &[PathBuf], format: Option<&str>) {
+    match format.unwrap_or("text") {
+        "json" => {
+            let paths = outputs
+                .iter()
+                .map(|output| json::string(&output.to_string_lossy()))
+                .collect::<Vec<_>>();
+            println!("[{}]", paths.join(", "));
+        }
+        // The raw bytes, so any path survives the trip to xargs -0
+        "null" => {
+            let mut out = stdout().lock();
+            for output in outputs {
+                let _ = out.write_all(output.as_os_str().as_encoded_bytes());
+                let _ = out.write_all(b"\0");
+            }
+            let _ = out.flush();
+        }
+        _ => {
+            for output in outputs {
+                println!("{}", output.to_str().unwrap_or("UNNAMED"));
+            }
+        }
+    }
+}
+
+/// Prints what `pandoc --list-output-formats` says, the values --to accepts
+fn list_formats() {
+    let output = match Command::new("pandoc").arg("--list-output-formats").output() {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!(
+                "{} Could not find suitable pandoc installation ({})",
+                theme::error(),
+                e
+            );
+            exit(EXIT_NO_PANDOC);
+        }
+    };
+    if !output.status.success() {
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+        eprintln!(
+            "{} pandoc could not list its output formats ({})",
+            theme::error(),
+            output.status
+        );
+        exit(EXIT_NO_PANDOC);
+    }
+    let _ = stdout().write_all(&output.stdout);
+}
+
+/// Prints the settings in effect after merging the configuration file and the flags, as TOML
+fn print_config(opts: &Options, src_dir: &Path, html_dir: &Path, config_file: &Path) {
+    #[derive(Serialize)]
+    struct Effective<'a> {
+        input: &'a Path,
+        output: &'a Path,
+        config: Option<&'a Path>,
+        cache: PathBuf,
+        to: &'a str,
+        math: String,
+        jobs: usize,
+        extensions: Vec<&'a str>,
+        pandoc_args: &'a [String],
+        lua_filters: &'a [PathBuf],
+        data_dir: Option<&'a Path>,
+        filter_cmd: Option<&'a str>,
+        // Tables have to come after plain values in TOML
+        formats: BTreeMap<&'a str, &'a str>,
+        rules: Vec<&'a Rule>,
+    }
+
+    let mut extensions = vec!["tex"];
+    extensions.extend(opts.extensions.iter().map(|ext| ext.as_str()));
+    let effective = Effective {
+        input: src_dir,
+        output: html_dir,
+        config: Some(config_file).filter(|file| file.is_file()),
+        cache: src_dir.join(".compilador_banco"),
+        to: opts.to.as_deref().unwrap_or("html"),
+        math: opts.math_flag(),
+        jobs: opts.jobs(),
+        extensions,
+        pandoc_args: &opts.pandoc_args,
+        lua_filters: &opts.lua_filters,
+        data_dir: opts.data_dir.as_deref(),
+        filter_cmd: opts.filter_cmd.as_deref(),
+        formats: opts
+            .format_map
+            .iter()
+            .map(|(ext, format)| (ext.as_str(), format.as_str()))
+            .collect(),
+        rules: opts.rules.iter().map(|(_, rule)| rule).collect(),
+    };
+
+    match toml::to_string(&effective) {
+        Ok(config) => print!("{}", config),
+        Err(e) => {
+            eprintln!("{} Could not print configuration ({})", theme::error(), e);
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+/// Prints which entries of the times table this run added, updated and removed
+fn print_cache_diff(before: &HashMap<String, Entry>, after: &HashMap<String, Entry>) {
+    let mut added = after
+        .keys()
+        .filter(|path| !before.contains_key(*path))
+        .collect::<Vec<_>>();
+    let mut updated = after
+        .iter()
+        .filter(|(path, entry)| before.get(*path).is_some_and(|old| old != *entry))
+        .map(|(path, _)| path)
+        .collect::<Vec<_>>();
+    let mut removed = before
+        .keys()
+        .filter(|path| !after.contains_key(*path))
+        .collect::<Vec<_>>();
+    if added.is_empty() && updated.is_empty() && removed.is_empty() {
+        println!("{} Modification times table unchanged", theme::info());
+        return;
+    }
+
+    println!("{} Modification times table changes:", theme::info());
+    for (change, paths) in [
+        ("added", &mut added),
+        ("updated", &mut updated),
+        ("removed", &mut removed),
+    ] {
+        if paths.is_empty() {
+            continue;
+        }
+        paths.sort();
+        println!("  {} {}:", paths.len(), change);
+        for path in paths.iter() {
+            println!("    {}", path);
+        }
+    }
+}
+
+/// Writes the cache to `file` with paths relative to `src_dir`
+fn export_cache(src_dir: &Path, file: &Path) {
+    let root = src_dir.canonicalize().unwrap_or(src_dir.to_path_buf());
+    match cache::export(&get_times(src_dir), &root, file) {
+        Ok(count) => println!(
+            "{} Exported {} cache entries to {}",
+            theme::info(),
+            count,
+            file.to_str().unwrap_or("UNNAMED")
+        ),
+        Err(e) => {
+            eprintln!(
+                "{} Could not export the cache to {} ({})",
+                theme::error(),
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_IO);
+        }
+    }
+}
+
+// What --init writes into a new bank
+const EXAMPLE_TEX: &str = "\\documentclass{article}
+\\title{Ecuación de segundo grado}
+
+\\begin{document}
+\\maketitle
+
+Resuelve $x^2 - 5x + 6 = 0$.
+
+% Bigger banks put each topic in its own directory; the html directory mirrors them
+\\end{document}
+";
+const EXAMPLE_CONFIG: &str =
+    "# Settings for every run over this directory; command line flags take precedence
+
+# Output format of the files no rule below matches
+# to = \"html\"
+
+# Extra arguments passed to pandoc, before the --pandoc-arg ones
+# pandoc_args = [\"--standalone\"]
+
+# Settings for the sources under the paths matching a glob; the first matching rule wins
+# [[rules]]
+# path = \"slides/**\"
+# to = \"revealjs\"
+# math = \"mathjax\"
+";
+const EXAMPLE_GITIGNORE: &str = "# Generated by compilador_banco
+/html/
+/.compilador_banco
+/.compilador_banco.failures
+/.compilador_banco.lock
+/.compilador_banco.snapshots/
+";
+
+/// Creates `dir` with an example source, a commented configuration and a `.gitignore`, leaving
+/// alone the files that already exist
+fn init_bank(dir: &Path) {
+    if let Err(e) = create_dir_all(dir) {
+        eprintln!(
+            "{} Could not create {} ({})",
+            theme::error(),
+            dir.to_str().unwrap_or("UNNAMED"),
+            e
+        );
+        exit(EXIT_IO);
+    }
+    for (name, contents) in [
+        ("ejemplo.tex", EXAMPLE_TEX),
+        (CONFIG_FILE, EXAMPLE_CONFIG),
+        (".gitignore", EXAMPLE_GITIGNORE),
+    ] {
+        let file = dir.join(name);
+        if file.exists() {
+            println!(
+                "{} {} already exists, leaving it as is",
+                theme::warn(),
+                name
+            );
+            continue;
+        }
+        if let Err(e) = write(&file, contents) {
+            eprintln!(
+                "{} Could not write {} ({})",
+                theme::error(),
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_IO);
+        }
+        println!(
+            "{} Created {}",
+            theme::info(),
+            file.to_str().unwrap_or("UNNAMED")
+        );
+    }
+}
+
+/// Copies the cache of `src_dir` to the snapshot `name`, or back from it with `restore`
+fn snapshot_cache(src_dir: &Path, name: &str, restore: bool) {
+    let cache = src_dir.join(".compilador_banco");
+    let snapshot = src_dir.join(".compilador_banco.snapshots").join(name);
+    let (from, to) = match restore {
+        true => (&snapshot, &cache),
+        false => (&cache, &snapshot),
+    };
+    if restore && !snapshot.is_file() {
+        eprintln!("{} There is no snapshot named {}", theme::error(), name);
+        exit(EXIT_USAGE);
+    }
+
+    let result = to
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|_| copy(from, to));
+    match result {
+        Ok(_) if restore => println!(
+            "{} Restored the cache from snapshot {}",
+            theme::info(),
+            name
+        ),
+        Ok(_) => println!(
+            "{} Saved the cache as snapshot {} ({})",
+            theme::info(),
+            name,
+            snapshot.to_str().unwrap_or("UNNAMED")
+        ),
+        Err(e) => {
+            eprintln!(
+                "{} Could not copy {} to {} ({})",
+                theme::error(),
+                from.to_str().unwrap_or("UNNAMED"),
+                to.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_IO);
+        }
+    }
+}
+
+/// Checks every cache entry against the files it describes, exiting with [`EXIT_INCONSISTENT`]
+/// if any doesn't match
+fn verify_cache(src_dir: &Path, html_dir: &Path, rename: Option<&RenameMap>, opts: &Options) {
+    let times = get_times(src_dir);
+    let root = src_dir.canonicalize().unwrap_or(src_dir.to_path_buf());
+    let names = (opts.name_by_title || opts.number_outputs).then(|| {
+        OutputNames::new(
+            &numbered_sources(src_dir, opts),
+            opts.name_by_title,
+            opts.number_outputs,
+        )
+    });
+
+    let mut problems: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    let mut filenames = times.entries.keys().collect::<Vec<_>>();
+    filenames.sort();
+    for filename in filenames {
+        let entry = &times.entries[filename];
+        let path = Path::new(filename);
+        let problem = if !path.exists() {
+            "source missing"
+        } else if newest_input(path).0 > entry.modified {
+            "source changed since it was compiled"
+        } else if let (Ok(relative), false) = (path.strip_prefix(&root), opts.is_source(path)) {
+            // Copied by --copy-dir
+            if html_dir.join(relative).exists() {
+                continue;
+            }
+            "output missing"
+        } else if let Ok(relative) = path.strip_prefix(&root) {
+            let output = output_path(
+                &src_dir.join(relative),
+                src_dir,
+                html_dir,
+                rename,
+                names.as_ref(),
+                opts,
+            );
+            let output = match opts.gzip_only {
+                true => gzip::path(&output),
+                false => output,
+            };
+            if !output.exists() {
+                "output missing"
+            } else if !entry.output_hash.is_empty() && file_hash(&output) != entry.output_hash {
+                "output modified since it was built"
+            } else {
+                continue;
+            }
+        } else {
+            "outside the input directory"
+        };
+        problems.entry(problem).or_default().push(filename);
+    }
+
+    if problems.is_empty() {
+        println!(
+            "{} All {} cache entries match the files",
+            theme::info(),
+            times.entries.len()
+        );
+        return;
+    }
+    let count = problems.values().map(|files| files.len()).sum::<usize>();
+    println!(
+        "{} {} of {} cache entries don't match the files",
+        theme::warn(),
+        count,
+        times.entries.len()
+    );
+    for (problem, files) in problems {
+        println!("\n{} ({}):", Paint::new(problem).bold(), files.len());
+        for file in files {
+            println!("  {}", file);
+        }
+    }
+    exit(EXIT_INCONSISTENT);
+}
+
+/// Merges the entries exported to `file` into the cache of `src_dir`, keeping the newer of two
+/// entries for the same source, or replaces the cache with them with --overwrite
+fn import_cache(src_dir: &Path, file: &Path, opts: &Options) {
+    let root = src_dir.canonicalize().unwrap_or(src_dir.to_path_buf());
+    let entries = match cache::import(file, &root) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!(
+                "{} Could not import the cache from {} ({})",
+                theme::error(),
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_USAGE);
+        }
+    };
+
+    let mut times = get_times(src_dir);
+    if opts.overwrite {
+        times.entries.clear();
+    }
+    let mut imported = 0;
+    for (path, entry) in entries {
+        if times
+            .get(&path)
+            .is_none_or(|existing| entry.modified > existing.modified)
+        {
+            times.insert(path, entry);
+            imported += 1;
+        }
+    }
+    save_times(src_dir, times);
+    println!(
+        "{} Imported {} cache entries from {}",
+        theme::info(),
+        imported,
+        file.to_str().unwrap_or("UNNAMED")
+    );
+}
+
+/// Takes the advisory lock on `.compilador_banco.lock` in `src_dir`, retrying for up to `wait`
+/// while another run holds it
+fn lock_dir(src_dir: &Path, wait: Option<Duration>) -> Option<File> {
+    let path = src_dir.join(".compilador_banco.lock");
+    // Without a place for the lock (e.g. a read-only bank) the run goes ahead unguarded
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .ok()?;
+    let started = Instant::now();
+    let mut announced = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Some(file),
+            Err(std::fs::TryLockError::WouldBlock) => {}
+            Err(std::fs::TryLockError::Error(_)) => return None,
+        }
+        match wait {
+            Some(wait) if started.elapsed() < wait => {
+                if !announced {
+                    println!(
+                        "{} Another run holds {}, waiting up to {}s",
+                        theme::info(),
+                        path.to_str().unwrap_or("UNNAMED"),
+                        wait.as_secs()
+                    );
+                    announced = true;
+                }
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            _ => {
+                eprintln!(
+                    "{} Another run holds {}{}",
+                    theme::error(),
+                    path.to_str().unwrap_or("UNNAMED"),
+                    match wait {
+                        Some(_) => ", gave up waiting for it",
+                        None => "; use --wait-for-lock to wait for it",
+                    }
+                );
+                exit(EXIT_IO);
+            }
+        }
+    }
+}
+
+/// Compiles `path` to a standalone HTML page in the temporary directory and opens it
+fn preview(path: &Path, opts: &mut Options) {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    let output = std::env::temp_dir().join(format!(
+        "compilador_banco-preview-{}-{}.html",
+        process::id(),
+        name
+    ));
+    if !opts
+        .pandoc_args
+        .iter()
+        .any(|arg| arg == "-s" || arg == "--standalone")
+    {
+        opts.pandoc_args.push("--standalone".to_owned());
+    }
+    let file_args = opts
+        .file_math_flag(Path::new(""))
+        .into_iter()
+        .collect::<Vec<_>>();
+    if let Err(e) = compile(path, Some(&output), "html", &file_args, opts) {
+        eprintln!(
+            "{} ./{}: {}",
+            theme::error(),
+            path.to_str().unwrap_or("UNNAMED"),
+            e
+        );
+        exit(EXIT_COMPILE);
+    }
+
+    #[cfg(target_os = "macos")]
+    let mut cmd = Command::new("open");
+    #[cfg(target_os = "windows")]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        cmd.args(["/C", "start", ""]);
+        cmd
+    };
+    #[cfg(not(any(target_os = "macos", target_os = "windows")))]
+    let mut cmd = Command::new("xdg-open");
+    println!(
+        "{} Opening {}",
+        theme::info(),
+        output.to_str().unwrap_or("UNNAMED")
+    );
+    match cmd.arg(&output).status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("{} Could not open the preview ({})", theme::error(), status),
+        Err(e) => eprintln!("{} Could not open the preview ({})", theme::error(), e),
+    }
+}
+
+/// Compiles `files` into a temporary directory, leaving the cache and the html directory alone,
+/// and reports how their outputs compare to those in `reference`
+fn diff_against(
+    files: &[PathBuf],
+    reference: &Path,
+    src_dir: &Path,
+    html_dir: &Path,
+    rename: Option<&RenameMap>,
+    opts: &Options,
+) {
+    if !reference.is_dir() {
+        eprintln!(
+            "{} Reference directory {} does not exist",
+            theme::error(),
+            reference.to_str().unwrap_or("UNNAMED")
+        );
+        exit(EXIT_USAGE);
+    }
+    let temp = std::env::temp_dir().join(format!("compilador_banco-diff-{}", process::id()));
+    // Nothing of the build may land in the real output, or run as if it had
+    let opts = &Options {
+        extract_media: opts.extract_media.as_ref().map(|media| {
+            let relative = media.strip_prefix(html_dir).unwrap_or(Path::new("media"));
+            temp.join(relative)
+        }),
+        on_file: None,
+        ..opts.clone()
+    };
+    let offsets = opts
+        .continue_numbering
+        .then(|| number_offsets(&numbered_sources(src_dir, opts)));
+    let names = (opts.name_by_title || opts.number_outputs).then(|| {
+        OutputNames::new(
+            &numbered_sources(src_dir, opts),
+            opts.name_by_title,
+            opts.number_outputs,
+        )
+    });
+    let mut failed = 0;
+    for path in files.iter().filter(|path| path.is_file()) {
+        let output = output_path(path, src_dir, &temp, rename, names.as_ref(), opts);
+        let format = opts.output_format(path.strip_prefix(src_dir).unwrap());
+        let file_args = file_args(path, src_dir, offsets.as_ref(), opts);
+        if let Err(e) = build_output(path, &output, format, &file_args, None, opts) {
+            eprintln!(
+                "{} ./{}: {}",
+                theme::error(),
+                path.strip_prefix(src_dir)
+                    .unwrap_or(path)
+                    .to_str()
+                    .unwrap_or("UNNAMED"),
+                e
+            );
+            failed += 1;
+        }
+    }
+
+    // Only outputs are compared, not whatever else was put next to them
+    let extensions = opts.output_extensions();
+    let outputs = |dir: &Path| {
+        find_files(dir)
+            .into_iter()
+            .filter(|file| {
+                file.extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| extensions.contains(&ext))
+            })
+            .filter_map(|file| Some(file.strip_prefix(dir).ok()?.to_path_buf()))
+            .collect::<BTreeSet<_>>()
+    };
+    let (built, expected) = (outputs(&temp), outputs(reference));
+    let mut report: BTreeMap<&str, Vec<&PathBuf>> = BTreeMap::new();
+    for file in built.union(&expected) {
+        let status = match (built.contains(file), expected.contains(file)) {
+            (true, false) => "new",
+            (false, _) => "removed",
+            _ if read(temp.join(file)).ok() == read(reference.join(file)).ok() => "unchanged",
+            _ => "changed",
+        };
+        report.entry(status).or_default().push(file);
+    }
+    let _ = remove_dir_all(&temp);
+
+    for (status, files) in &report {
+        println!("\n{} ({}):", Paint::new(status).bold(), files.len());
+        for file in files {
+            println!("  {}", file.to_str().unwrap_or("UNNAMED"));
+        }
+    }
+    let differing = report
+        .iter()
+        .filter(|(status, _)| **status != "unchanged")
+        .map(|(_, files)| files.len())
+        .sum::<usize>();
+    println!(
+        "\n{} {} of {} outputs differ from {}",
+        if differing == 0 {
+            theme::info()
+        } else {
+            theme::warn()
+        },
+        differing,
+        built.union(&expected).count(),
+        reference.to_str().unwrap_or("UNNAMED")
+    );
+    if failed > 0 {
+        exit(EXIT_COMPILE);
+    }
+    if differing > 0 {
+        exit(EXIT_INCONSISTENT);
+    }
+}
+
+/// Prints what [`latex::lint`] finds in each of `files`, exiting with EXIT_COMPILE if anything
+fn lint(files: &[PathBuf], cwd: &Path) {
+    let mut problems = 0;
+    for file in files {
+        let name = file
+            .strip_prefix(cwd)
+            .unwrap_or(file)
+            .to_str()
+            .unwrap_or("UNNAMED");
+        let source = match read(file) {
+            Ok(source) => String::from_utf8_lossy(&source).into_owned(),
+            Err(e) => {
+                eprintln!("{} ./{}: Could not read it ({})", theme::error(), name, e);
+                problems += 1;
+                continue;
+            }
+        };
+        for (line, problem) in latex::lint(&source) {
+            println!("{} ./{}:{}: {}", theme::warn(), name, line, problem);
+            problems += 1;
+        }
+    }
+    println!(
+        "{} Checked {} files, {} problems",
+        theme::info(),
+        files.len(),
+        problems
+    );
+    if problems > 0 {
+        exit(EXIT_COMPILE);
+    }
+}
+
+/// Records `files` in the cache as compiled as they are now, without running pandoc
+fn touch(
+    files: &[PathBuf],
+    src_dir: &Path,
+    html_dir: &Path,
+    rename: Option<&RenameMap>,
+    opts: &Options,
+) {
+    let mut times = get_times(src_dir);
+    // pandoc isn't looked up for --touch, the last one seen stands in for it
+    let version = times.header.get("pandoc_version").cloned();
+    let fingerprint = shared_fingerprint(opts, version.as_deref());
+    let offsets = opts
+        .continue_numbering
+        .then(|| number_offsets(&numbered_sources(src_dir, opts)));
+    let names = (opts.name_by_title || opts.number_outputs).then(|| {
+        OutputNames::new(
+            &numbered_sources(src_dir, opts),
+            opts.name_by_title,
+            opts.number_outputs,
+        )
+    });
+    let mut recorded = 0;
+    for path in files.iter().filter(|path| path.is_file()) {
+        let output = output_path(path, src_dir, html_dir, rename, names.as_ref(), opts);
+        let built = match opts.gzip_only {
+            true => gzip::path(&output),
+            false => output,
+        };
+        times.insert(
+            cache_key(path),
+            Entry {
+                modified: newest_input(path).0,
+                fingerprint: file_fingerprint(&fingerprint, path, src_dir, offsets.as_ref(), opts),
+                // A missing output is still built by the next run
+                output_hash: file_hash(&built),
+                source_hash: file_hash(path),
+                built: None,
+            },
+        );
+        recorded += 1;
+    }
+    save_times(src_dir, times);
+    println!(
+        "{} Recorded {} files as compiled without running pandoc",
+        theme::info(),
+        recorded
+    );
+}
+
+/// Gives each of `files` without a cache entry the entry of a source that's gone, if it hashes
+/// the same with the same `fingerprint`: the file was renamed (or moved), so its output is moved
+/// along instead of compiling it again. Returns the old key, new source and output of each
+fn carry_over_renames(
+    files: &[PathBuf],
+    src_dir: &Path,
+    times: &mut Times,
+    output_path: &dyn Fn(&Path) -> PathBuf,
+    fingerprint: &dyn Fn(&Path) -> String,
+    opts: &Options,
+) -> Vec<(String, PathBuf, PathBuf)> {
+    let mut renamed = Vec::new();
+    // Section pages are named after the output, they'd all need moving too
+    if opts.split_sections {
+        return renamed;
+    }
+    let root = src_dir.canonicalize().unwrap_or(src_dir.to_path_buf());
+    let mut gone = times
+        .entries
+        .iter()
+        .filter(|(key, entry)| !entry.source_hash.is_empty() && !Path::new(key).exists())
+        .filter_map(|(key, entry)| {
+            let relative = Path::new(key).strip_prefix(&root).ok()?;
+            Some((key.clone(), src_dir.join(relative), entry.clone()))
+        })
+        .collect::<Vec<_>>();
+
+    for path in files {
+        if gone.is_empty() {
+            break;
+        }
+        let key = match path.canonicalize() {
+            Ok(key) => key.to_str().unwrap_or_default().to_owned(),
+            Err(_) => continue,
+        };
+        if times.get(&key).is_some() {
+            continue;
+        }
+        let (hash, fingerprint) = (file_hash(path), fingerprint(path));
+        let found = gone.iter().position(|(_, _, entry)| {
+            entry.source_hash == hash && entry.fingerprint == fingerprint
+        });
+        let (old_key, old_path, entry) = match found {
+            Some(found) => gone.remove(found),
+            None => continue,
+        };
+
+        let (old_output, output) = (output_path(&old_path), output_path(path));
+        let mut moves = vec![
+            (gzip::path(&old_output), gzip::path(&output)),
+            (
+                old_output.with_extension("txt"),
+                output.with_extension("txt"),
+            ),
+        ];
+        moves.retain(|(from, _)| from.is_file());
+        if !opts.gzip_only {
+            moves.push((old_output.clone(), output.clone()));
+        }
+        let moved = old_output == output
+            || moves.iter().all(|(from, to)| {
+                to.parent().map_or(Ok(()), create_dir_all).is_ok()
+                    && std::fs::rename(from, to).is_ok()
+            });
+        if !moved {
+            continue;
+        }
+
+        times.entries.remove(&old_key);
+        times.insert(
+            key,
+            Entry {
+                modified: newest_input(path).0,
+                ..entry
+            },
+        );
+        if !opts.summary_only {
+            println!(
+                "{} ./{}: Renamed from ./{}, moved its output instead of compiling it again",
+                theme::info(),
+                path.strip_prefix(src_dir)
+                    .unwrap_or(path)
+                    .to_str()
+                    .unwrap_or("UNNAMED"),
+                old_path
+                    .strip_prefix(src_dir)
+                    .unwrap_or(&old_path)
+                    .to_str()
+                    .unwrap_or("UNNAMED")
+            );
+        }
+        renamed.push((old_key, path.clone(), output));
+    }
+    renamed
+}
+
+/// Every source, in the order --number-outputs and --continue-numbering count them: the
+/// manifest's when there is one, by name otherwise
+fn numbered_sources(src_dir: &Path, opts: &Options) -> Vec<PathBuf> {
+    match opts.manifest {
+        Some(_) => opts
+            .manifest_sources
+            .iter()
+            .map(|file| src_dir.join(file))
+            .collect(),
+        None => find_tex(src_dir, opts),
+    }
+}
+
+/// Where the section numbers of each of `sources` start with --continue-numbering: how many
+/// numbered sections the sources before it have
+fn number_offsets(sources: &[PathBuf]) -> HashMap<PathBuf, usize> {
+    let mut offsets = HashMap::new();
+    let mut offset = 0;
+    for source in sources {
+        let sections = read_to_string(source).map_or(0, |source| latex::numbered_sections(&source));
+        offsets.insert(source.clone(), offset);
+        offset += sections;
+    }
+    offsets
+}
+
+/// Arguments pandoc gets for `path` alone: its math renderer, where its numbering starts and
+/// the defaults file of its format
+fn file_args(
+    path: &Path,
+    src_dir: &Path,
+    offsets: Option<&HashMap<PathBuf, usize>>,
+    opts: &Options,
+) -> Vec<String> {
+    let relative = path.strip_prefix(src_dir).unwrap_or(path);
+    let mut args = opts
+        .file_math_flag(relative)
+        .into_iter()
+        .collect::<Vec<_>>();
+    if let Some(offsets) = offsets {
+        args.push("--number-sections".to_owned());
+        if let Some(offset) = offsets.get(path).filter(|offset| **offset > 0) {
+            args.push(format!("--number-offset={}", offset));
+        }
+    }
+    if let Some(defaults) = format_defaults(src_dir, opts.output_format(relative)) {
+        args.push(format!("--defaults={}", defaults.to_str().unwrap()));
+    }
+    args
+}
+
+/// The `defaults.FORMAT.yaml` pandoc defaults file in `src_dir` for the output `format`, if any
+fn format_defaults(src_dir: &Path, format: &str) -> Option<PathBuf> {
+    let defaults = src_dir.join(format!("defaults.{}.yaml", format));
+    defaults.is_file().then_some(defaults)
+}
+
+/// `fingerprint` with the settings only `path` has: a configuration rule's math renderer, its
+/// --continue-numbering offset, the defaults file of its format and, if it cites anything, the
+/// --bibliography, which change its output like the shared inputs do
+fn file_fingerprint(
+    fingerprint: &str,
+    path: &Path,
+    src_dir: &Path,
+    offsets: Option<&HashMap<PathBuf, usize>>,
+    opts: &Options,
+) -> String {
+    let relative = path.strip_prefix(src_dir).unwrap_or(path);
+    let fingerprint = cache::with_setting(fingerprint, opts.rule_math(relative));
+    let offset = offsets
+        .and_then(|offsets| offsets.get(path))
+        .map(|offset| format!("offset={}", offset));
+    let fingerprint = cache::with_setting(&fingerprint, offset.as_deref());
+    let defaults = format_defaults(src_dir, opts.output_format(relative))
+        .map(|defaults| cache::fingerprint(&[defaults]));
+    let fingerprint = cache::with_setting(&fingerprint, defaults.as_deref());
+    let bibliography = opts
+        .bibliography
+        .as_ref()
+        .filter(|_| deps::cites(path))
+        .map(|bibliography| cache::fingerprint(std::slice::from_ref(bibliography)));
+    cache::with_setting(&fingerprint, bibliography.as_deref())
+}
+
+/// Fingerprint of the inputs every document depends on (with the --join-sep after --prepend), of
+/// the --output-encoding and of the pandoc `version` rendering them; changing any of them
+/// invalidates the whole table
+fn shared_fingerprint(opts: &Options, version: Option<&str>) -> String {
+    let mut shared = opts.lua_filters.clone();
+    shared.extend(
+        opts.include_before
+            .iter()
+            .chain(&opts.include_after)
+            .cloned(),
+    );
+    shared.extend(opts.prepend.clone());
+    shared.extend(opts.epub_cover.iter().chain(&opts.epub_metadata).cloned());
+    if let Some(dir) = &opts.data_dir {
+        let mut resources = find_files(dir);
+        resources.sort();
+        shared.extend(resources);
+    }
+    let fingerprint = cache::with_setting(&cache::fingerprint(&shared), opts.join_sep.as_deref());
+    let encoding = opts.output_encoding.map(|encoding| encoding.name());
+    let fingerprint = cache::with_setting(&fingerprint, encoding);
+    cache::with_setting(&fingerprint, version)
+}
+
+/// Removes the output directory and the tables kept next to the sources
+fn clean(src_dir: &Path, html_dir: &Path, opts: &Options) {
+    let targets: Vec<PathBuf> = vec![
+        html_dir.to_path_buf(),
+        src_dir.join(".compilador_banco"),
+        src_dir.join(".compilador_banco.failures"),
+    ]
+    .into_iter()
+    .filter(|target| target.exists())
+    .collect();
+
+    if targets.is_empty() {
+        println!("{} Nothing to clean", theme::info());
+        return;
+    }
+    if !confirm("remove", src_dir, &targets, opts) {
+        println!("{} Clean aborted", theme::info());
+        return;
+    }
+
+    for target in &targets {
+        let result = if target.is_dir() {
+            remove_dir_all(target)
+        } else {
+            remove_file(target)
+        };
+        if let Err(e) = result {
+            eprintln!(
+                "{} Failed to remove {} ({})",
+                theme::error(),
+                target.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+        }
+    }
+    println!(
+        "{} Removed {} generated items",
+        theme::info(),
+        targets.len()
+    );
+}
+
+/// Removes outputs (and their .gz copies) in `html_dir` that no longer correspond to any source
+fn prune(
+    cwd: &Path,
+    src_dir: &Path,
+    html_dir: &Path,
+    rename: Option<&RenameMap>,
+    names: Option<&OutputNames>,
+    opts: &Options,
+) {
+    let mut expected: HashSet<PathBuf> = find_tex(src_dir, opts)
+        .iter()
+        .flat_map(|file| {
+            let output = output_path(file, src_dir, html_dir, rename, names, opts);
+            let mut outputs = section_outputs(file, &output, opts);
+            outputs.extend(text_output(&output, opts));
+            outputs.push(output);
+            outputs
+        })
+        .collect();
+    // Written by this run too, though no source is behind them: the copied assets and the
+    // --master-toc page
+    expected.extend(
+        assets(src_dir, html_dir, opts)
+            .into_iter()
+            .map(|(_, target)| target),
+    );
+    if let Some(toc) = &opts.master_toc {
+        expected.insert(cwd.join(toc));
+    }
+    let extensions = opts.output_extensions();
+    let stale: Vec<PathBuf> = find_files(html_dir)
+        .into_iter()
+        .filter_map(|file| {
+            // Compressed copies belong to the same source as the HTML they were made from
+            let html = match file.to_str()?.strip_suffix(".gz") {
+                Some(html) => PathBuf::from(html),
+                None => file.clone(),
+            };
+            let ext = html.extension().unwrap_or_default();
+            let listing =
+                opts.dir_index && html.file_name().is_some_and(|name| name == "index.html");
+            match extensions.iter().any(|e| ext == *e) && !expected.contains(&html) && !listing {
+                true => Some(file),
+                false => None,
+            }
+        })
+        .collect();
+
+    if stale.is_empty() {
+        println!("{} Nothing to prune", theme::info());
+        return;
+    }
+    if !confirm("prune", html_dir, &stale, opts) {
+        println!("{} Prune aborted", theme::info());
+        return;
+    }
+
+    for file in &stale {
+        if let Err(e) = remove_file(file) {
+            eprintln!(
+                "{} Failed to remove {} ({})",
+                theme::error(),
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+        }
+    }
+    remove_empty_dirs(html_dir);
+    println!("{} Pruned {} stale outputs", theme::info(), stale.len());
+}
+
+/// Asks before deleting `targets`, unless --yes was given or nobody is there to answer
+fn confirm(action: &str, dir: &Path, targets: &[PathBuf], opts: &Options) -> bool {
+    if opts.yes || !stdout().is_terminal() {
+        return true;
+    }
+
+    let dir = dir.canonicalize().unwrap_or(dir.to_path_buf());
+    println!(
+        "{} About to {} in {}:",
+        theme::warn(),
+        action,
+        Paint::new(dir.to_str().unwrap_or("UNNAMED")).bold()
+    );
+    for target in targets {
+        let target = target.canonicalize().unwrap_or(target.to_path_buf());
+        println!(
+            "  {}",
+            target
+                .strip_prefix(&dir)
+                .unwrap_or(&target)
+                .to_str()
+                .unwrap_or("UNNAMED")
+        );
+    }
+    print!("Proceed? [y/N] ");
+    stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    stdin().read_line(&mut answer).unwrap_or_default();
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Creates and removes a file in `dir`, since permission bits alone miss read-only mounts and ACLs
+fn check_writable(dir: &Path) -> std::io::Result<()> {
+    let probe = dir.join(format!(".compilador_banco-probe-{}", process::id()));
+    File::create(&probe)?;
+    remove_file(probe)
+}
+
+/// Files matching the glob `pattern`, relative to `cwd`, sorted
+fn expand_glob(cwd: &Path, pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let (base, rest) = glob::split_base(pattern);
+    let glob = Glob::new(rest)?;
+    let base = cwd.join(base);
+    let mut files: Vec<PathBuf> = find_files(&base)
+        .into_iter()
+        .filter(|file| {
+            file.strip_prefix(&base)
+                .ok()
+                .and_then(|relative| relative.to_str())
+                .is_some_and(|relative| glob.is_match(&relative.replace('\\', "/")))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Deepest directory containing every one of `files`
+fn common_ancestor(files: &[PathBuf]) -> PathBuf {
+    let mut ancestor = files[0].parent().unwrap_or(Path::new("/")).to_path_buf();
+    for file in files {
+        while !file.starts_with(&ancestor) {
+            ancestor.pop();
+        }
+    }
+    ancestor
+}
+
+/// Removes the directories under `dir` left empty, keeping `dir` itself
+fn remove_empty_dirs(dir: &Path) {
+    if let Ok(read) = read_dir(dir) {
+        for item in read.filter_map(|item| item.ok()) {
+            let item = item.path();
+            if item.is_dir() {
+                remove_empty_dirs(&item);
+                // Only succeeds when empty
+                let _ = remove_dir(&item);
+            }
+        }
+    }
+}
+
+/// Prints per-directory and total content statistics of the sources under `src_dir`
+fn print_stats(src_dir: &Path, opts: &Options) {
+    let mut dirs: BTreeMap<PathBuf, Stats> = BTreeMap::new();
+    for file in find_tex(src_dir, opts) {
+        let source = match read_to_string(&file) {
+            Ok(source) => source,
+            Err(e) => {
+                println!(
+                    "{} Could not read {} ({})",
+                    theme::warn(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+                continue;
+            }
+        };
+        let dir = file
+            .parent()
+            .and_then(|dir| dir.strip_prefix(src_dir).ok())
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        *dirs.entry(dir).or_default() += latex::stats(&source);
+    }
+
+    let mut total = Stats::default();
+    println!(
+        "{:>6} {:>9} {:>6} {:>6}  directory",
+        "files", "sections", "items", "math"
+    );
+    for (dir, stats) in dirs {
+        println!(
+            "{:>6} {:>9} {:>6} {:>6}  ./{}",
+            stats.files,
+            stats.sections,
+            stats.items,
+            stats.math,
+            dir.to_str().unwrap_or("UNNAMED")
+        );
+        total += stats;
+    }
+    println!(
+        "{}",
+        Paint::new(format!(
+            "{:>6} {:>9} {:>6} {:>6}  total",
+            total.files, total.sections, total.items, total.math
+        ))
+        .bold()
+    );
+}
+
+/// Writes the listings of `documents` asked for (--sitemap, --index, --master-toc and
+/// --dir-index), after removing the outputs with no source left under --prune, and checks the
+/// links of the pages with --validate-links
+fn update_site(
+    cwd: &Path,
+    documents: &[(PathBuf, PathBuf)],
+    src_dir: &Path,
+    html_dir: &Path,
+    rename: Option<&RenameMap>,
+    names: Option<&OutputNames>,
+    opts: &Options,
+) {
+    if opts.sitemap {
+        match site::write_sitemap(documents, html_dir, opts) {
+            Ok(sitemap) if opts.verbose => println!(
+                "{} Wrote {}",
+                theme::info(),
+                sitemap.to_str().unwrap_or("UNNAMED")
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!("{} Failed to write sitemap ({})", theme::error(), e),
+        }
+    }
+
+    if let Some(index) = &opts.index {
+        if let Err(e) = site::write_index(&cwd.join(index), documents, src_dir, html_dir, opts) {
+            eprintln!(
+                "{} Failed to write index {} ({})",
+                theme::error(),
+                index.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+        }
+    }
+
+    if let Some(toc) = &opts.master_toc {
+        match site::write_master_toc(&cwd.join(toc), documents, html_dir, opts) {
+            Ok(true) if opts.verbose => println!(
+                "{} Updated {}",
+                theme::info(),
+                toc.to_str().unwrap_or("UNNAMED")
+            ),
+            Ok(_) => {}
+            Err(e) => eprintln!(
+                "{} Failed to write table of contents {} ({})",
+                theme::error(),
+                toc.to_str().unwrap_or("UNNAMED"),
+                e
+            ),
+        }
+    }
+
+    if opts.prune {
+        prune(cwd, src_dir, html_dir, rename, names, opts);
+    }
+
+    if opts.dir_index {
+        let written = site::write_dir_indexes(html_dir, html_dir, documents);
+        if written > 0 && opts.verbose {
+            println!("{} Updated {} directory listings", theme::info(), written);
+        }
+    }
+
+    if opts.validate_links {
+        site::validate_links(html_dir, cwd, opts);
+    }
+}
+
+/// Formats a duration like `3m12s`
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+/// Formats a byte count using binary units, e.g. `8.3 MiB`
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Runs pandoc on `path`, writing `format` to `output` (or stdout when there's none)
+fn compile(
+    path: &Path,
+    output: Option<&Path>,
+    format: &str,
+    file_args: &[String],
+    opts: &Options,
+) -> Result<(), String> {
+    let source = prepared_source(path, opts)?;
+    compile_source(path, source, output, format, file_args, opts)
+}
+
+/// Runs pandoc on `source` in place of the contents of `path`, or on `path` itself without one,
+/// adding the [`file_args`] of `path`
+fn compile_source(
+    path: &Path,
+    source: Option<Vec<u8>>,
+    output: Option<&Path>,
+    format: &str,
+    file_args: &[String],
+    opts: &Options,
+) -> Result<(), String> {
+    let mut cmd = match opts.limits_pandoc() {
+        true => Command::new("prlimit"),
+        false => Command::new("pandoc"),
+    };
+    if opts.limits_pandoc() {
+        if let Some(bytes) = opts.mem_limit {
+            cmd.arg(format!("--as={}", bytes));
+        }
+        if let Some(secs) = opts.cpu_limit {
+            cmd.arg(format!("--cpu={}", secs));
+        }
+        cmd.args(["--", "pandoc"]);
+    }
+    if source.is_none() {
+        cmd.arg(path);
+    }
+    cmd.args(["-f", opts.input_format(path)]);
+    // So `\input{sibling}` resolves next to the source
+    match path.parent() {
+        Some(dir) if !opts.keep_cwd && dir != Path::new("") => {
+            cmd.current_dir(dir);
+        }
+        _ => (),
+    }
+    // pandoc has no pdf writer, it picks one from the output's extension instead
+    if format != "pdf" {
+        cmd.args(["-t", format]);
+    } else {
+        cmd.arg(format!("--pdf-engine={}", opts.pdf_engine()));
+    }
+    if let Some(output) = output {
+        cmd.args(["-o", output.to_str().unwrap()]);
+    }
+    cmd.args(file_args);
+    if let Some(media) = &opts.extract_media {
+        cmd.arg(format!("--extract-media={}", media.to_str().unwrap()));
+    }
+    if let Some(dir) = &opts.data_dir {
+        cmd.arg(format!("--data-dir={}", dir.to_str().unwrap()));
+    }
+    if extension(format) == "epub" {
+        if let Some(cover) = &opts.epub_cover {
+            cmd.arg(format!("--epub-cover-image={}", cover.to_str().unwrap()));
+        }
+        if let Some(metadata) = &opts.epub_metadata {
+            cmd.arg(format!("--epub-metadata={}", metadata.to_str().unwrap()));
+        }
+    }
+    for filter in &opts.lua_filters {
+        cmd.arg(format!("--lua-filter={}", filter.to_str().unwrap()));
+    }
+    if let Some(bibliography) = &opts.bibliography {
+        cmd.arg("--citeproc");
+        cmd.arg(format!("--bibliography={}", bibliography.to_str().unwrap()));
+    }
+    for file in &opts.include_before {
+        cmd.arg(format!("--include-before-body={}", file.to_str().unwrap()));
+    }
+    for file in &opts.include_after {
+        cmd.arg(format!("--include-after-body={}", file.to_str().unwrap()));
+    }
+    cmd.args(&opts.pandoc_args);
+    let metadata = match metadata::sidecar(path) {
+        Some(sidecar) => Some(metadata::pandoc_file(&sidecar).map_err(|e| {
+            let file = sidecar.to_str().unwrap_or("UNNAMED");
+            format!("Invalid metadata {} ({})", file, e)
+        })?),
+        None => None,
+    };
+    if let Some((file, _)) = &metadata {
+        cmd.arg(format!("--metadata-file={}", file.to_str().unwrap()));
+    }
+    if path != Path::new("-") {
+        let sidecar = Sidecar::load(path).map_err(|e| {
+            let file = sidecar_path(path);
+            format!(
+                "Invalid sidecar {} ({})",
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            )
+        })?;
+        for arg in &sidecar.pandoc_args {
+            cmd.arg(expand_env(arg, opts.allow_undefined_env)?);
+        }
+    }
+    cmd.args(&opts.passthrough_args);
+
+    let timeout = file_timeout(path, opts)?;
+
+    // Captured for the warning summary, then passed on unless --summary-only hides per-file output
+    cmd.stderr(Stdio::piped());
+    if source.is_some() {
+        cmd.stdin(Stdio::piped());
+    }
+    let mut child = cmd
+        .spawn()
+        .map_err(|e| format!("Could not run pandoc ({})", e))?;
+    let mut stderr = child.stderr.take().unwrap();
+    // Read while writing stdin, or a chatty pandoc could block on a full pipe
+    let reader = std::thread::spawn(move || {
+        let mut captured = String::new();
+        let _ = stderr.read_to_string(&mut captured);
+        captured
+    });
+    if let Some(source) = source {
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&source)
+            .map_err(|e| format!("Could not write to pandoc ({})", e))?;
+    }
+    let (status, timed_out) = wait_with_timeout(&mut child, timeout);
+    let captured = reader.join().unwrap_or_default();
+    let allowed = status
+        .code()
+        .is_some_and(|code| opts.ok_exit_codes.contains(&code));
+    let failed = !status.success() && !allowed;
+    // --quiet-pandoc and --compact only hide what pandoc says about compilations that worked;
+    // the warnings summary keeps just the [WARNING] lines, so errors would be lost otherwise
+    if !opts.summary_only && (failed || !(opts.quiet_pandoc || opts.compact)) {
+        eprint!("{}", captured);
+    }
+    warnings::record(path, &captured);
+    if let Some((file, true)) = &metadata {
+        let _ = remove_file(file);
+    }
+
+    if let Some(timeout) = timeout.filter(|_| timed_out) {
+        return Err(format!(
+            "pandoc timed out after {}",
+            human_duration(timeout)
+        ));
+    }
+    if failed {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            // SIGXCPU, or SIGKILL once past the hard limit
+            if opts.limits_pandoc() && status.signal().is_some() {
+                return Err(format!(
+                    "pandoc was stopped by its resource limits ({})",
+                    status
+                ));
+            }
+        }
+        return Err(format!("pandoc failed ({})", status));
+    }
+    Ok(())
+}
+
+/// How long pandoc may take on `path`: what its `%% cb: timeout=SECS` line asks for, up to
+/// --max-timeout, or else --timeout
+fn file_timeout(path: &Path, opts: &Options) -> Result<Option<Duration>, String> {
+    let marker = read_to_string(path)
+        .ok()
+        .and_then(|source| latex::marker(&source, "cb"));
+    let asked = marker.iter().flat_map(|settings| {
+        settings
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter_map(|setting| setting.strip_prefix("timeout="))
+    });
+    match asked.last() {
+        Some(secs) => match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => {
+                let secs = opts.max_timeout.map_or(secs, |max| secs.min(max));
+                Ok(Some(Duration::from_secs(secs)))
+            }
+            _ => Err(format!("Invalid `%% cb: timeout={}` (expected SECS)", secs)),
+        },
+        None => Ok(opts.timeout.map(Duration::from_secs)),
+    }
+}
+
+/// Waits for `child`, killing it once it runs for longer than `timeout`; whether it had to is the
+/// second value
+fn wait_with_timeout(
+    child: &mut process::Child,
+    timeout: Option<Duration>,
+) -> (process::ExitStatus, bool) {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return (child.wait().expect("Command wasn't running"), false),
+    };
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait().expect("Command wasn't running") {
+            return (status, false);
+        }
+        if started.elapsed() >= timeout {
+            let _ = child.kill();
+            return (child.wait().expect("Command wasn't running"), true);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Runs pandoc on `path`, honouring --skip-identical-output, --cas-dir, --gzip and --on-file,
+/// returning whether `output` was written; with --embed-fingerprint an HTML output ends with
+/// `stamp` (see [`output_stamp`]), which also keys it in --cas-dir
+fn build_output(
+    path: &Path,
+    output: &Path,
+    format: &str,
+    file_args: &[String],
+    stamp: Option<&str>,
+    opts: &Options,
+) -> Result<bool, String> {
+    // Media extracted next to the output isn't kept with it
+    let cas = opts
+        .cas_dir
+        .as_deref()
+        .zip(stamp)
+        .filter(|_| opts.extract_media.is_none())
+        .map(|(dir, stamp)| (dir, cas_key(stamp, format, file_args, opts)));
+    let stamp = stamp.filter(|_| opts.embed_fingerprint && extension(format) == "html");
+    if let Some(cycle) = deps::cycle(path) {
+        let files = cycle
+            .iter()
+            .map(|file| file.to_str().unwrap_or("UNNAMED"))
+            .collect::<Vec<_>>();
+        return Err(format!("Circular \\input ({})", files.join(" -> ")));
+    }
+    create_dir_all(output.parent().unwrap_or(Path::new("/"))).map_err(|e| e.to_string())?;
+    let html = extension(format) == "html";
+    let normalize = opts.normalize_output && html;
+    if opts.split_sections && html {
+        let sections = split_sources(path, output, opts)?;
+        if !sections.is_empty() {
+            let written = build_sections(path, output, format, file_args, sections, opts)?;
+            if let Some(stamp) = stamp.filter(|_| written) {
+                append_stamp(output, stamp)?;
+            }
+            // The text of the whole document, since search results link to the source anyway
+            if let Some(text) = text_output(output, opts).filter(|text| written || !text.exists()) {
+                compile(path, Some(&text), "plain", file_args, opts)?;
+            }
+            return Ok(written);
+        }
+    }
+    let cached = match &cas {
+        Some((dir, key)) => cas::fetch(dir, key, output)
+            .map_err(|e| format!("Could not read --cas-dir ({})", e))?
+            .map(|changed| changed || !opts.skip_identical_output),
+        None => None,
+    };
+    let written = if let Some(written) = cached {
+        written
+    } else if opts.skip_identical_output {
+        let temp = PathBuf::from(format!("{}.tmp", output.to_str().unwrap()));
+        let result = compile(path, Some(&temp), format, file_args, opts)
+            .and_then(|_| match (&opts.extract_media, html) {
+                (Some(media), true) => relink_media(&temp, media),
+                _ => Ok(()),
+            })
+            .and_then(|_| match normalize {
+                true => normalize_output(&temp),
+                false => Ok(()),
+            })
+            .and_then(|_| match html {
+                true => transcode_output(&temp, opts),
+                false => Ok(()),
+            })
+            .and_then(|_| match stamp {
+                Some(stamp) => append_stamp(&temp, stamp),
+                None => Ok(()),
+            })
+            .and_then(|_| replace_if_changed(&temp, output));
+        // Already renamed into place unless something failed
+        let _ = remove_file(&temp);
+        result?
+    } else {
+        compile(path, Some(output), format, file_args, opts)?;
+        if let (Some(media), true) = (&opts.extract_media, html) {
+            relink_media(output, media)?;
+        }
+        if normalize {
+            normalize_output(output)?;
+        }
+        if html {
+            transcode_output(output, opts)?;
+        }
+        if let Some(stamp) = stamp {
+            append_stamp(output, stamp)?;
+        }
+        true
+    };
+    if let Some((dir, key)) = cas.as_ref().filter(|_| cached.is_none()) {
+        cas::store(dir, key, output).map_err(|e| format!("Could not write --cas-dir ({})", e))?;
+    }
+    if let Some(text) = text_output(output, opts).filter(|text| written || !text.exists()) {
+        compile(path, Some(&text), "plain", file_args, opts)?;
+    }
+    if written && (opts.gzip || opts.gzip_only) {
+        gzip_output(output, opts.gzip_only)?;
+    }
+    if let Some(command) = opts.on_file.as_deref().filter(|_| written) {
+        // --gzip-only has already removed the output, its compressed copy is what's left
+        let output = match opts.gzip_only {
+            true => gzip::path(output),
+            false => output.to_path_buf(),
+        };
+        let status = shell(command)
+            .env("CB_SOURCE", path)
+            .env("CB_OUTPUT", &output)
+            .status()
+            .map_err(|e| format!("Could not run --on-file `{}` ({})", command, e))?;
+        if !status.success() {
+            return Err(format!("--on-file `{}` failed ({})", command, status));
+        }
+    }
+    Ok(written)
+}
+
+/// Key of the --cas-dir entry for an output built from `stamp` (see [`output_stamp`]) as
+/// `format` with `file_args`, telling apart every option that changes the output's bytes
+fn cas_key(stamp: &str, format: &str, file_args: &[String], opts: &Options) -> String {
+    let mut parts = vec![stamp, format];
+    parts.extend(file_args.iter().map(String::as_str));
+    parts.extend(opts.filter_cmd.as_deref());
+    let strip_lines = opts.strip_lines.map(|lines| lines.to_string());
+    parts.extend(strip_lines.as_deref());
+    // Only when set, so the entries stored without them still match
+    for (set, flag) in [
+        (opts.skip_preamble, "skip-preamble"),
+        (opts.normalize_output, "normalize-output"),
+        (opts.embed_fingerprint, "embed-fingerprint"),
+    ] {
+        if set {
+            parts.push(flag);
+        }
+    }
+    cas::key(&parts)
+}
 
-    let cwd = current_dir().unwrap_or(PathBuf::from("/"));
+/// For `--split-by section`, the output, title and source of each section of `path`, named
+/// after the section next to `output`; empty when it has no sections
+fn split_sources(
+    path: &Path,
+    output: &Path,
+    opts: &Options,
+) -> Result<Vec<(PathBuf, String, Vec<u8>)>, String> {
+    let source = match prepared_source(path, opts)? {
+        Some(source) => source,
+        None => read(path).map_err(|e| e.to_string())?,
+    };
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = output.extension().unwrap_or_default().to_string_lossy();
+    let mut taken: HashMap<String, usize> = HashMap::new();
+    let mut sections = Vec::new();
+    for (i, (title, text)) in latex::sections(&String::from_utf8_lossy(&source))
+        .into_iter()
+        .enumerate()
+    {
+        let slug = match latex::slugify(&title) {
+            slug if slug.is_empty() => (i + 1).to_string(),
+            slug => slug,
+        };
+        let count = taken.entry(slug.clone()).or_insert(0);
+        *count += 1;
+        let name = match *count {
+            1 => format!("{}-{}.{}", stem, slug, ext),
+            n => format!("{}-{}-{}.{}", stem, slug, n, ext),
+        };
+        sections.push((output.with_file_name(name), title, text.into_bytes()));
+    }
+    Ok(sections)
+}
 
-    if let Err(e) = which("pandoc") {
-        eprintln!(
-            "{} Could not find suitable pandoc installation ({})",
-            Paint::red("ERROR").invert().bold(),
-            e,
-        );
-        exit(1);
+/// The `.txt` `--also-text` writes next to an HTML `output`
+fn text_output(output: &Path, opts: &Options) -> Option<PathBuf> {
+    (opts.also_text && output.extension().is_some_and(|ext| ext == "html"))
+        .then(|| output.with_extension("txt"))
+}
+
+/// Outputs `--split-by section` writes for `path` besides `output`
+fn section_outputs(path: &Path, output: &Path, opts: &Options) -> Vec<PathBuf> {
+    if !opts.split_sections || output.extension().is_none_or(|ext| ext != "html") {
+        return vec![];
+    }
+    split_sources(path, output, opts)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(output, _, _)| output)
+        .collect()
+}
+
+/// Compiles each section to its own page, and makes `output` a contents page linking to them
+fn build_sections(
+    path: &Path,
+    output: &Path,
+    format: &str,
+    file_args: &[String],
+    sections: Vec<(PathBuf, String, Vec<u8>)>,
+    opts: &Options,
+) -> Result<bool, String> {
+    let mut items = Vec::new();
+    for (page, title, source) in sections {
+        compile_source(path, Some(source), Some(&page), format, file_args, opts)?;
+        if let Some(media) = &opts.extract_media {
+            relink_media(&page, media)?;
+        }
+        if opts.normalize_output {
+            normalize_output(&page)?;
+        }
+        transcode_output(&page, opts)?;
+        items.push(format!(
+            "<li><a href=\"{}\">{}</a></li>",
+            site::xml_escape(&page.file_name().unwrap_or_default().to_string_lossy()),
+            site::xml_escape(&title)
+        ));
+    }
+    let heading = read_to_string(path)
+        .ok()
+        .and_then(|source| latex::title(&source))
+        .unwrap_or_else(|| {
+            output
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned()
+        });
+    let contents = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<ol>\n{1}\n</ol>\n</body>\n</html>\n",
+        site::xml_escape(&heading),
+        items.join("\n")
+    );
+    write(output, contents).map_err(|e| e.to_string())?;
+    transcode_output(output, opts)?;
+    Ok(true)
+}
+
+/// Points the references pandoc wrote to the absolute `--extract-media` directory at the same
+/// files relative to `output`, so the HTML keeps working wherever html/ is served from
+fn relink_media(output: &Path, media: &Path) -> Result<(), String> {
+    let contents = read_to_string(output).map_err(|e| e.to_string())?;
+    let dir = output.parent().unwrap_or(Path::new("/"));
+    let common = dir
+        .ancestors()
+        .find(|ancestor| media.starts_with(ancestor))
+        .unwrap_or(Path::new("/"));
+    let mut relative = PathBuf::new();
+    for _ in dir.strip_prefix(common).unwrap().components() {
+        relative.push("..");
     }
+    relative.push(media.strip_prefix(common).unwrap());
 
-    let args: Vec<String> = args()
-        .collect::<Vec<String>>()
+    let relinked = contents.replace(
+        media.to_str().unwrap(),
+        &relative.to_string_lossy().replace('\\', "/"),
+    );
+    if relinked != contents {
+        write(output, relinked).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Files in the `--extract-media` directory and their modification times, if it's set
+fn media_files(opts: &Options) -> Option<HashMap<PathBuf, SystemTime>> {
+    let media = opts.extract_media.as_ref()?;
+    let files = find_files(media)
         .into_iter()
-        .skip(1)
+        .filter_map(|file| {
+            let modified = file.metadata().and_then(|metadata| metadata.modified());
+            Some((file, modified.ok()?))
+        })
         .collect();
+    Some(files)
+}
 
-    let html_dir = cwd
-        .join(args.get(0).unwrap_or(&("".to_owned())))
-        .join("html");
-    if !html_dir.exists() {
-        if let Err(e) = create_dir(&html_dir) {
+/// Strips a leading UTF-8 BOM from `output` and leaves it ending in exactly one newline
+fn normalize_output(output: &Path) -> Result<(), String> {
+    let contents = read(output).map_err(|e| e.to_string())?;
+    let contents = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&contents);
+    let end = contents
+        .iter()
+        .rposition(|byte| !matches!(byte, b'\n' | b'\r'))
+        .map_or(0, |last| last + 1);
+    let mut normalized = contents[..end].to_vec();
+    normalized.push(b'\n');
+    if normalized != contents {
+        write(output, normalized).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Rewrites the HTML `output` in the --output-encoding, with a `<meta charset>` saying so; left
+/// in UTF-8, with a warning, if it has characters the encoding lacks
+fn transcode_output(output: &Path, opts: &Options) -> Result<(), String> {
+    let encoding = match opts.output_encoding {
+        Some(encoding) => encoding,
+        None => return Ok(()),
+    };
+    let contents = read_to_string(output).map_err(|e| e.to_string())?;
+    let meta = Regex::new(r#"(?i)<meta\s+charset\s*=\s*["']?utf-8["']?"#).unwrap();
+    let charset = format!("<meta charset=\"{}\"", encoding.name());
+    let contents = match meta.is_match(&contents) {
+        true => meta.replace(&contents, charset.as_str()).into_owned(),
+        false => match Regex::new(r"(?i)<head[^>]*>").unwrap().find(&contents) {
+            Some(head) => format!(
+                "{}{}>{}",
+                &contents[..head.end()],
+                charset,
+                &contents[head.end()..]
+            ),
+            None => contents,
+        },
+    };
+    match encoding.encode(&contents) {
+        Ok(encoded) => write(output, encoded).map_err(|e| e.to_string()),
+        Err(c) => {
             eprintln!(
-                "{} Error while creating html directory ({})",
-                Paint::red("ERROR").invert().bold(),
-                e,
+                "{} {}: Left in UTF-8, as {} has no U+{:04X} ({})",
+                theme::warn(),
+                output.to_str().unwrap_or("UNNAMED"),
+                encoding.name(),
+                c as u32,
+                c
             );
-            exit(1);
+            Ok(())
         }
     }
+}
 
-    let src_dir = cwd.join(&args.get(0).unwrap_or(&("".to_owned())));
-    let mut times = get_times(&src_dir);
+/// What `output` is built from, for --embed-fingerprint: hashes of `path` with everything it
+/// depends on, of the pandoc arguments, and of the shared inputs and per-file settings in
+/// `fingerprint`
+fn output_stamp(path: &Path, output: &Path, fingerprint: &str, opts: &Options) -> String {
+    let mut sources = read(path).unwrap_or_default();
+    for file in deps::transitive(path) {
+        sources.extend(cache::portable(&file).as_bytes());
+        sources.extend(read(&file).unwrap_or_default());
+    }
+    let mut args = vec![
+        opts.input_format(path).to_owned(),
+        output
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        opts.math_flag(),
+    ];
+    args.extend(opts.pandoc_args.iter().cloned());
+    args.extend(opts.passthrough_args.iter().cloned());
+    format!(
+        "source={} args={} template={}",
+        hash::digest(&sources),
+        hash::digest(args.join("\0").as_bytes()),
+        hash::digest(fingerprint.as_bytes())
+    )
+}
 
-    let files = find_tex(&src_dir);
-    for file in files {
-        let path = cwd.join(file);
-        if !path.exists() {
-            eprintln!(
-                "{} ./{}: File does not exist",
-                Paint::red("ERROR").invert().bold(),
-                Paint::new(
-                    path.strip_prefix(&cwd) // TODO Strip src_dir instead?
-                        .unwrap()
-                        .as_os_str()
-                        .to_str()
-                        .unwrap_or("UNNAMED")
-                )
-                .bold(),
-                // Paint::red("File does not exist")
-            );
-            continue;
-        } else if changed(&path, &times)
-            || !PathBuf::from(
-                html_dir
-                    .join(path.strip_prefix(&src_dir).unwrap())
-                    .to_str()
-                    .unwrap()
-                    .rsplit_once(".")
+/// Ends `output` with an HTML comment holding `stamp`
+fn append_stamp(output: &Path, stamp: &str) -> Result<(), String> {
+    let mut file = File::options()
+        .append(true)
+        .open(output)
+        .map_err(|e| e.to_string())?;
+    writeln!(file, "<!-- compilador_banco {} -->", stamp).map_err(|e| e.to_string())
+}
+
+/// The stamp [`append_stamp`] left at the end of `output`, if any
+fn embedded_stamp(output: &Path) -> Option<String> {
+    let contents = read(output).ok()?;
+    let tail = String::from_utf8_lossy(&contents[contents.len().saturating_sub(512)..]);
+    let start = tail.rfind("<!-- compilador_banco ")? + "<!-- compilador_banco ".len();
+    let end = start + tail[start..].find(" -->")?;
+    Some(tail[start..end].to_owned())
+}
+
+/// Builds every `(source, output, file_args, stamp)` in `stale` with `threads` pandoc processes
+/// at a time
+fn build_parallel(
+    mut stale: Vec<(PathBuf, PathBuf, Vec<String>, Option<String>)>,
+    src_dir: &Path,
+    opts: &Options,
+    threads: usize,
+) -> HashMap<PathBuf, (Result<bool, String>, Duration)> {
+    // Taken from the back, so they start in the --compile-order
+    stale.reverse();
+    let queue = Mutex::new(stale);
+    let results = Mutex::new(HashMap::new());
+    let failed = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                // With --fail-fast nothing new starts once something failed
+                if opts.fail_fast && failed.load(Ordering::Relaxed) {
+                    return;
+                }
+                let (path, output, file_args, stamp) = match queue.lock().unwrap().pop() {
+                    Some(next) => next,
+                    None => return,
+                };
+                let format = opts.output_format(path.strip_prefix(src_dir).unwrap());
+                let started = Instant::now();
+                let result =
+                    build_output(&path, &output, format, &file_args, stamp.as_deref(), opts);
+                failed.fetch_or(result.is_err(), Ordering::Relaxed);
+                results
+                    .lock()
                     .unwrap()
-                    .0
-                    .to_owned()
-                    + ".html",
+                    .insert(path, (result, started.elapsed()));
+            });
+        }
+    });
+
+    results.into_inner().unwrap()
+}
+
+/// Writes a gzip-compressed copy of `output` next to it, optionally replacing it
+fn gzip_output(output: &Path, only: bool) -> Result<(), String> {
+    let html = read(output).map_err(|e| e.to_string())?;
+    let gz = gzip::path(output);
+    File::create(&gz)
+        .and_then(|mut file| file.write_all(&gzip::compress(&html)))
+        .map_err(|e| {
+            format!(
+                "Could not write {} ({})",
+                gz.to_str().unwrap_or("UNNAMED"),
+                e
             )
-            .exists()
+        })?;
+    if only {
+        remove_file(output).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Syncs the contents of `html_dir` to the rsync destination `target`
+fn deploy(html_dir: &Path, target: &str, opts: &Options) {
+    let mut cmd = Command::new("rsync");
+    cmd.arg("--archive");
+    match opts.deploy_dry_run {
+        true => cmd.args(["--dry-run", "--itemize-changes"]),
+        false => cmd.arg("--info=progress2"),
+    };
+    // The trailing separator makes rsync copy what's inside rather than the directory itself
+    let mut source = html_dir.as_os_str().to_owned();
+    source.push("/");
+    cmd.arg(source).arg(target);
+
+    println!(
+        "{} {} {} to {}",
+        theme::info(),
+        if opts.deploy_dry_run {
+            "Would deploy"
+        } else {
+            "Deploying"
+        },
+        html_dir.to_str().unwrap_or("UNNAMED"),
+        target
+    );
+    match cmd.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            eprintln!("{} rsync failed ({})", theme::error(), status);
+            exit(EXIT_IO);
+        }
+        Err(e) => {
+            eprintln!("{} Could not run rsync ({})", theme::error(), e);
+            exit(EXIT_IO);
+        }
+    }
+}
+
+/// The files under `src_dir` matching a `--copy-dir`, each with where its copy goes in `html_dir`
+fn assets(src_dir: &Path, html_dir: &Path, opts: &Options) -> Vec<(PathBuf, PathBuf)> {
+    if opts.copy_dirs.is_empty() {
+        return vec![];
+    }
+
+    let mut files = find_files(src_dir);
+    files.sort();
+    files
+        .into_iter()
+        .filter(|file| !file.starts_with(html_dir))
+        .filter_map(|file| {
+            let relative = file.strip_prefix(src_dir).unwrap();
+            let name = relative.to_string_lossy().replace('\\', "/");
+            let target = html_dir.join(relative);
+            opts.copy_dirs
+                .iter()
+                .any(|glob| glob.contains(&name))
+                .then_some((file, target))
+        })
+        .collect()
+}
+
+/// Copies the files under `src_dir` matching a `--copy-dir` to the same place under `html_dir`,
+/// skipping those unchanged since the copy recorded in `times`, and returns the copies made
+fn copy_assets(src_dir: &Path, html_dir: &Path, times: &mut Times, opts: &Options) -> Vec<PathBuf> {
+    let mut copied = Vec::new();
+    for (file, target) in assets(src_dir, html_dir, opts) {
+        let key = match file.canonicalize() {
+            Ok(key) => key.to_str().unwrap_or_default().to_owned(),
+            Err(e) => {
+                eprintln!(
+                    "{} Could not resolve {} ({})",
+                    theme::error(),
+                    file.to_str().unwrap_or("UNNAMED"),
+                    e
+                );
+                continue;
+            }
+        };
+        let modified = mtime(&file);
+        if target.exists()
+            && times
+                .get(&key)
+                .is_some_and(|entry| entry.modified >= modified)
         {
-            println!(
-                "{} ./{}: Compiling LaTeX to HTML",
-                Paint::cyan("INFO").invert().bold(),
-                Paint::new(
-                    path.strip_prefix(&cwd)
-                        .unwrap()
-                        .as_os_str()
-                        .to_str()
-                        .unwrap_or("UNNAMED")
-                )
-                .bold()
-            );
-            #[cfg(not(target_os = "windows"))]
-            let sp = Spinner::new(&Spinners::OrangeBluePulse, "Executing pandoc".into());
-            create_dir_all(
-                html_dir
-                    .join(path.strip_prefix(&src_dir).unwrap())
-                    .parent()
-                    .unwrap_or(Path::new("/")),
-            )
-            .unwrap();
-            let mut cmd = Command::new("pandoc")
-                .args([
-                    &path.to_str().unwrap(),
-                    "-f",
-                    "latex",
-                    "-t",
-                    "html",
-                    "-o",
-                    &(html_dir
-                        .join(path.strip_prefix(&src_dir).unwrap())
-                        .to_str()
-                        .unwrap()
-                        .rsplit_once(".")
-                        .unwrap()
-                        .0
-                        .to_owned()
-                        + ".html"),
-                    "--katex",
-                ])
-                .spawn()
-                .unwrap();
-            cmd.wait().expect("Command wasn't running");
-            #[cfg(not(target_os = "windows"))]
+            continue;
+        }
+        let result = target
+            .parent()
+            .map_or(Ok(()), create_dir_all)
+            .and_then(|_| copy(&file, &target));
+        match result {
+            Ok(_) => {
+                times.insert(
+                    key,
+                    Entry {
+                        modified,
+                        fingerprint: String::new(),
+                        output_hash: String::new(),
+                        source_hash: String::new(),
+                        built: None,
+                    },
+                );
+                copied.push(target);
+            }
+            Err(e) => eprintln!(
+                "{} Could not copy {} ({})",
+                theme::error(),
+                file.strip_prefix(src_dir)
+                    .unwrap_or(&file)
+                    .to_string_lossy(),
+                e
+            ),
+        }
+    }
+    copied
+}
+
+/// Gives `output` (and its .gz copy) the modification time of `source`
+fn copy_mtime(source: &Path, output: &Path) -> std::io::Result<()> {
+    let modified = source.metadata()?.modified()?;
+    for file in [output.to_path_buf(), gzip::path(output)] {
+        if file.exists() {
+            File::options()
+                .write(true)
+                .open(&file)?
+                .set_modified(modified)?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives `files` the --chmod mode, and the directories from `html_dir` down to them the
+/// --chmod-dirs one
+#[cfg(unix)]
+fn chmod_outputs(files: &[PathBuf], html_dir: &Path, opts: &Options) -> std::io::Result<()> {
+    use std::fs::{set_permissions, Permissions};
+    use std::os::unix::fs::PermissionsExt;
+
+    for file in files {
+        if let Some(mode) = opts.chmod {
+            set_permissions(file, Permissions::from_mode(mode))?;
+        }
+        if let Some(mode) = opts.chmod_dirs {
+            for dir in file
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| dir.starts_with(html_dir))
             {
-                sp.message("Successfully compiled \u{2705}\n".to_owned());
-                std::thread::sleep(std::time::Duration::from_millis(90)); // Give time to change message
-                sp.stop();
+                set_permissions(dir, Permissions::from_mode(mode))?;
             }
-            #[cfg(target_os = "windows")]
-            println!(
-                "Successfully compiled {} \u{2705}",
-                path.strip_prefix(&cwd)
-                    .unwrap()
-                    .as_os_str()
-                    .to_str()
-                    .unwrap_or("UNNAMED")
-            );
-        } else {
-            println!(
-                "{} ./{}: No changes since last compilation",
-                Paint::cyan("INFO").invert().bold(),
-                Paint::new(
-                    path.strip_prefix(&cwd)
-                        .unwrap()
-                        .as_os_str()
-                        .to_str()
-                        .unwrap_or("UNNAMED")
-                )
-                .bold()
-            );
         }
+    }
+    Ok(())
+}
 
-        // Update compilation time in save_times
-        times.insert(
-            path.canonicalize().unwrap().to_str().unwrap().to_owned(),
-            Utc::now().timestamp().to_string(),
-        );
+/// Windows has no mode bits, --chmod and --chmod-dirs are ignored there
+#[cfg(not(unix))]
+fn chmod_outputs(_files: &[PathBuf], _html_dir: &Path, _opts: &Options) -> std::io::Result<()> {
+    Ok(())
+}
 
-        println!();
+/// Moves `temp` over `output` unless both have the same contents, returning whether it did
+fn replace_if_changed(temp: &Path, output: &Path) -> Result<bool, String> {
+    if output.exists() && read(temp).ok() == read(output).ok() {
+        return Ok(false);
     }
-    save_times(&src_dir, times);
+    rename(temp, output).map_err(|e| {
+        format!(
+            "Could not write {} ({})",
+            output.to_str().unwrap_or("UNNAMED"),
+            e
+        )
+    })?;
+    Ok(true)
 }
 
-fn find_tex(base: &PathBuf) -> Vec<PathBuf> {
-    let mut matches: Vec<PathBuf> = Vec::new();
-    if !base.is_dir() {
-        return vec![];
+/// What to feed pandoc on stdin instead of letting it read `path` itself, if anything: the
+/// output of `--filter-cmd`, minus the lines `--strip-lines` and `--skip-preamble` drop, after
+/// the `--prepend` file and the --join-sep line
+fn prepared_source(path: &Path, opts: &Options) -> Result<Option<Vec<u8>>, String> {
+    let preamble = match &opts.prepend {
+        Some(preamble) => read(preamble).map_err(|e| {
+            let file = preamble.to_str().unwrap_or("UNNAMED");
+            format!("Could not read {} ({})", file, e)
+        })?,
+        None => return processed_source(path, opts),
     };
-    match read_dir(base) {
-        Ok(read) => {
-            for item in read {
-                let item = item.unwrap().path();
-                if item.is_file() && item.extension().unwrap_or_default() == "tex" {
-                    matches.push(item);
-                } else if item.is_dir() {
-                    matches.append(&mut find_tex(&item))
-                }
-            }
+    let source = match processed_source(path, opts)? {
+        Some(source) => source,
+        None if path == Path::new("-") => {
+            let mut source = Vec::new();
+            stdin()
+                .read_to_end(&mut source)
+                .map_err(|e| format!("Could not read stdin ({})", e))?;
+            source
         }
-        Err(e) => println!(
-            "{} {} {}: {}",
-            Paint::yellow("WARN").invert().bold(),
-            Paint::new("Could not read directory"),
-            base.to_str().unwrap_or("UNKNOWN"),
-            e
-        ),
+        None => read(path).map_err(|e| e.to_string())?,
+    };
+    let mut prepended = preamble;
+    // A preamble ending in `%` or mid-command would swallow the first line of the source
+    if !prepended.ends_with(b"\n") {
+        prepended.push(b'\n');
+    }
+    if let Some(sep) = &opts.join_sep {
+        prepended.extend(sep.as_bytes());
+        prepended.push(b'\n');
+    }
+    prepended.extend(source);
+    Ok(Some(prepended))
+}
+
+fn processed_source(path: &Path, opts: &Options) -> Result<Option<Vec<u8>>, String> {
+    let strip = opts.strip_lines.is_some() || opts.skip_preamble;
+    let source = match &opts.filter_cmd {
+        Some(filter) => filter_source(path, filter)?,
+        None if strip && path == Path::new("-") => {
+            let mut source = Vec::new();
+            stdin()
+                .read_to_end(&mut source)
+                .map_err(|e| format!("Could not read stdin ({})", e))?;
+            source
+        }
+        None if strip => read(path).map_err(|e| e.to_string())?,
+        None => return Ok(None),
+    };
+    if !strip {
+        return Ok(Some(source));
+    }
+
+    let text = String::from_utf8_lossy(&source);
+    let lines = text.split_inclusive('\n').collect::<Vec<_>>();
+    let mut skip = opts.strip_lines.unwrap_or(0).min(lines.len());
+    if opts.skip_preamble {
+        let begin = lines
+            .iter()
+            .position(|line| latex::strip_comment(line).contains("\\begin{document}"));
+        skip = skip.max(begin.unwrap_or(0));
+    }
+    if opts.verbose {
+        // stderr, as with --stdout the document itself goes to stdout
+        eprintln!(
+            "{} {}: Stripped {} lines before passing it to pandoc",
+            theme::info(),
+            path.to_str().unwrap_or("UNNAMED"),
+            skip
+        );
+    }
+    Ok(Some(lines[skip..].concat().into_bytes()))
+}
+
+/// Contents of `path` after piping them through the user's `--filter-cmd`
+fn filter_source(path: &Path, filter: &str) -> Result<Vec<u8>, String> {
+    let source = if path == Path::new("-") {
+        Stdio::inherit()
+    } else {
+        File::open(path).map_err(|e| e.to_string())?.into()
+    };
+    let result = shell(filter)
+        .stdin(source)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Could not run filter `{}` ({})", filter, e))?;
+
+    if !result.status.success() {
+        return Err(format!("Filter `{}` failed ({})", filter, result.status));
+    }
+    Ok(result.stdout)
+}
+
+/// Command running `command` through the platform's shell
+fn shell(command: &str) -> Command {
+    #[cfg(not(target_os = "windows"))]
+    let mut cmd = Command::new("sh");
+    #[cfg(not(target_os = "windows"))]
+    cmd.arg("-c");
+    #[cfg(target_os = "windows")]
+    let mut cmd = Command::new("cmd");
+    #[cfg(target_os = "windows")]
+    cmd.arg("/C");
+
+    cmd.arg(command);
+    cmd
+}
+
+/// Path of the file `path` compiles to, mirroring its place under `src_dir`, inside a
+/// subdirectory for its `%% lang:` marker (or `--default-lang`) if it has one, and named after
+/// its title with `--name-by title`
+fn output_path(
+    path: &Path,
+    src_dir: &Path,
+    html_dir: &Path,
+    rename: Option<&RenameMap>,
+    names: Option<&OutputNames>,
+    opts: &Options,
+) -> PathBuf {
+    let relative = path.strip_prefix(src_dir).unwrap();
+    let mut name = match names.and_then(|names| names.get(path)) {
+        Some(title) => title.to_owned(),
+        None => relative
+            .file_stem()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap()
+            .to_owned(),
+    };
+    if let Some(rename) = rename {
+        name = rename.apply(&name);
+    }
+
+    let source = read_to_string(path).unwrap_or_default();
+    let lang = latex::marker(&source, "lang").or_else(|| opts.default_lang.clone());
+    let dir = relative.parent().unwrap_or(Path::new(""));
+    let ext = extension(opts.output_format(relative));
+    let html_dir = html_dir.join(lang.unwrap_or_default());
+    let template = match &opts.output_template {
+        Some(template) => template,
+        None => return html_dir.join(dir).join(name + "." + ext),
+    };
+
+    // A title made only of symbols slugifies to nothing, which would leave a nameless file
+    let stem = relative.file_stem().unwrap_or_default().to_string_lossy();
+    let title = latex::title(&source)
+        .map(|title| latex::slugify(&title))
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| stem.into_owned());
+    let mut output = template.clone();
+    for (placeholder, value) in [
+        ("{dir}", dir.to_str().unwrap_or_default().to_owned()),
+        ("{name}", name),
+        ("{ext}", ext.to_owned()),
+        ("{date}", Local::now().format("%Y-%m-%d").to_string()),
+        ("{title}", title),
+    ] {
+        output = output.replace(placeholder, &value);
     }
+    // `{dir}/{name}` with a file at the top leaves a leading slash that would make it absolute
+    html_dir.join(output.trim_start_matches('/'))
+}
 
-    matches
+/// Name of the directory next to `missing` whose name is closest to it, if any is close enough
+fn suggest_dir(missing: &Path) -> Option<String> {
+    let name = missing.file_name()?.to_str()?;
+    let parent = missing.parent()?;
+
+    read_dir(parent)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .map(|candidate| (edit_distance(name, &candidate), candidate))
+        .filter(|(distance, _)| *distance <= 2.max(name.chars().count() / 3))
+        .min()
+        .map(|(_, candidate)| candidate)
 }
 
-fn get_times(dir: &PathBuf) -> HashMap<String, String> {
-    let mut map = HashMap::new();
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
 
-    let contents = match read_to_string(dir.join(".compilador_banco")) {
-        Ok(res) => res,
+/// Files that failed to compile in the previous run
+fn get_failures(dir: &Path) -> Vec<PathBuf> {
+    match read_to_string(dir.join(".compilador_banco.failures")) {
+        Ok(contents) => contents.lines().map(PathBuf::from).collect(),
         Err(e) => {
             println!(
                 "{} {}: {}",
-                Paint::yellow("WARN").invert().bold(),
-                Paint::new("Load modification times table"),
+                theme::warn(),
+                Paint::new("Load failed files list"),
                 e
             );
-            return map;
+            vec![]
         }
-    };
+    }
+}
 
-    for line in contents.lines() {
-        let (filename, time) = line.split_once(";").unwrap();
-        map.insert(filename.to_owned(), time.to_owned());
+/// Writes the modification times table next to the sources, exiting if it can't be written
+fn save_times(dir: &Path, times: Times) {
+    if let Err(e) = cache::save_times(dir, times) {
+        eprintln!(
+            "{} Could not write the modification times table in {} ({})",
+            theme::error(),
+            dir.to_str().unwrap_or("UNNAMED"),
+            e
+        );
+        exit(EXIT_IO);
     }
+}
 
-    map
+/// Key of `path` in the modification times table: its canonical path, exiting if it can't be
+/// resolved
+fn cache_key(path: &Path) -> String {
+    match path.canonicalize() {
+        Ok(path) => path.to_str().unwrap_or_default().to_owned(),
+        Err(e) => {
+            eprintln!(
+                "{} Could not resolve {} ({})",
+                theme::error(),
+                path.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_IO);
+        }
+    }
 }
 
-// ! REMEMBER TO USE .canonicalize on all files before sending to save and also when comparing
-fn save_times(dir: &PathBuf, map: HashMap<String, String>) {
-    let mut saves_file = File::create(dir.join(".compilador_banco")).unwrap();
-    for (filename, time) in map {
-        if let Err(e) = writeln!(saves_file, "{};{}", filename, time) {
+fn save_failures(dir: &Path, failures: &[PathBuf]) {
+    let file = dir.join(".compilador_banco.failures");
+    if failures.is_empty() {
+        if file.exists() {
+            if let Err(e) = remove_file(&file) {
+                eprintln!(
+                    "{} Failed to remove failed files list ({})",
+                    theme::error(),
+                    e
+                );
+            }
+        }
+        return;
+    }
+
+    let mut failures_file = match File::create(&file) {
+        Ok(failures_file) => failures_file,
+        Err(e) => {
             eprintln!(
-                "{} Failed to write to saves file time for {} ({})",
-                Paint::red("ERROR").invert().bold(),
-                filename,
+                "{} Could not write failed files list {} ({})",
+                theme::error(),
+                file.to_str().unwrap_or("UNNAMED"),
+                e
+            );
+            exit(EXIT_IO);
+        }
+    };
+    for failure in failures {
+        let failure = failure.canonicalize().unwrap_or(failure.to_path_buf());
+        if let Err(e) = writeln!(failures_file, "{}", failure.to_str().unwrap()) {
+            eprintln!(
+                "{} Failed to write failed file {} ({})",
+                theme::error(),
+                failure.to_str().unwrap(),
                 e
             );
         };
     }
 }
 
-fn changed(path: &PathBuf, times: &HashMap<String, String>) -> bool {
-    DateTime::<Utc>::from(path.metadata().unwrap().modified().unwrap())
-        > DateTime::<Utc>::from_utc(
-            NaiveDateTime::parse_from_str(
-                times
-                    .get(path.canonicalize().unwrap().to_str().unwrap())
-                    .unwrap_or(&(String::from("0"))),
-                "%s",
+/// Whether `path` needs compiling, and why; `retrying` is whether this pass retries the failures
+/// of the previous run, which are compiled whatever their state
+#[allow(clippy::too_many_arguments)]
+fn staleness(
+    path: &Path,
+    src_dir: &Path,
+    output: &Path,
+    times: &Times,
+    fingerprint: &str,
+    git_changes: Option<&HashSet<PathBuf>>,
+    retrying: bool,
+    opts: &Options,
+) -> (bool, String) {
+    if let Some(pattern) = opts.assumed_unchanged(path.strip_prefix(src_dir).unwrap_or(path)) {
+        return (false, format!("assumed unchanged by {}", pattern));
+    }
+    if retrying {
+        return (true, "failed in the previous run".to_owned());
+    }
+    if opts.recent.is_some() {
+        return (true, "among the most recently modified".to_owned());
+    }
+    let text = text_output(output, opts);
+    // With --gzip-only the HTML itself is gone, but its compressed copy should be there
+    let output = &match opts.gzip_only {
+        true => gzip::path(output),
+        false => output.to_path_buf(),
+    };
+    if opts.since_last_run {
+        let last_run = match times.last_run() {
+            Some(last_run) => last_run,
+            None => return (true, "no successful run recorded".to_owned()),
+        };
+        return if newest_input(path).0 > last_run {
+            (true, "changed since the last run".to_owned())
+        } else if !output.exists() {
+            (true, "output missing".to_owned())
+        } else {
+            (false, "unchanged since the last run".to_owned())
+        };
+    }
+    if let Some(changes) = git_changes {
+        let since = opts.changed_since.as_deref().unwrap_or("HEAD");
+        let changed = |path: &Path| {
+            path.canonicalize()
+                .is_ok_and(|path| changes.contains(&path))
+        };
+        // Documents including a file changed in the range are rebuilt along with it
+        let include = opts
+            .changed_since
+            .is_some()
+            .then(|| {
+                deps::transitive(path)
+                    .into_iter()
+                    .find(|file| changed(file))
+            })
+            .flatten();
+        return if changed(path) {
+            (true, format!("changed since {}", since))
+        } else if let Some(include) = include {
+            (
+                true,
+                format!(
+                    "{} changed since {}",
+                    include.to_str().unwrap_or("UNNAMED"),
+                    since
+                ),
             )
-            .unwrap(),
-            Utc,
+        } else if !output.exists() && opts.git {
+            (true, "output missing".to_owned())
+        } else {
+            (false, format!("unchanged since {}", since))
+        };
+    }
+    if opts.only_missing {
+        return match output.exists() {
+            true => (false, "output exists".to_owned()),
+            false => (true, "output missing".to_owned()),
+        };
+    }
+    // Outputs built with --embed-fingerprint say what they were built from, which still holds
+    // when the cache is missing or a checkout gave the sources new modification times
+    let stamped = || {
+        opts.embed_fingerprint
+            && embedded_stamp(output) == Some(output_stamp(path, output, fingerprint, opts))
+    };
+    // A source that can't be resolved isn't looked up, compiling it reports what's wrong
+    let key = path.canonicalize().ok();
+    let entry = match key.as_ref().and_then(|key| times.get(key.to_str()?)) {
+        Some(entry) => entry,
+        None if stamped() => return (false, "fingerprint in the output matches".to_owned()),
+        None => return (true, "not in cache".to_owned()),
+    };
+
+    let (modified, include) = newest_input(path);
+    // Dated in the future, a source looks newer than its entry (kept to the time it was recorded)
+    // until the clock gets there, so its contents tell instead
+    let future_unchanged = || {
+        modified > Utc::now()
+            && include.is_none()
+            && !entry.source_hash.is_empty()
+            && file_hash(path) == entry.source_hash
+    };
+    let changed = modified > entry.modified && !future_unchanged();
+    if changed && stamped() {
+        (false, "fingerprint in the output matches".to_owned())
+    } else if changed {
+        let reason = match include {
+            Some(include) => format!("{} changed", include.to_str().unwrap_or("UNNAMED")),
+            None => format!(
+                "mtime {} > cached {}",
+                format_time(modified),
+                format_time(entry.modified)
+            ),
+        };
+        (true, reason)
+    } else if entry.fingerprint != fingerprint {
+        (true, "shared inputs changed".to_owned())
+    } else if !output.exists() {
+        (true, "output missing".to_owned())
+    } else if text.is_some_and(|text| !text.exists()) {
+        (true, "text output missing".to_owned())
+    } else if !entry.output_hash.is_empty() && file_hash(output) != entry.output_hash {
+        (true, "output modified since it was built".to_owned())
+    } else if opts.max_age.is_some_and(|max_age| {
+        // By when it was built rather than the output's mtime, which --preserve-times backdates
+        let built = match entry.built {
+            Some(built) => Some(built.into()),
+            None => output
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok(),
+        };
+        built.is_some_and(|built: SystemTime| built.elapsed().is_ok_and(|age| age > max_age))
+    }) {
+        (true, "output older than --max-age".to_owned())
+    } else if modified > entry.modified {
+        (false, "dated in the future, contents unchanged".to_owned())
+    } else {
+        (
+            false,
+            format!(
+                "mtime {} <= cached {}",
+                format_time(modified),
+                format_time(entry.modified)
+            ),
         )
+    }
+}
+
+/// First line of `pandoc --version`, remembered in the cache header along with the executable's
+/// path and mtime so it's only asked again after pandoc changes
+fn pandoc_version(pandoc: &Path, times: &mut Times) -> Option<String> {
+    let key = format!(
+        "{};{}",
+        pandoc.to_str()?,
+        format_time(mtime(&pandoc.canonicalize().ok()?))
+    );
+    if times.header.get("pandoc") == Some(&key) {
+        if let Some(version) = times.header.get("pandoc_version") {
+            return Some(version.clone());
+        }
+    }
+
+    let output = Command::new(pandoc).arg("--version").output().ok()?;
+    let version = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .next()?
+        .trim()
+        .to_owned();
+    times.header.insert("pandoc".to_owned(), key);
+    times
+        .header
+        .insert("pandoc_version".to_owned(), version.clone());
+    Some(version)
+}
+
+/// Files under `dir` that are untracked, or differ from HEAD in the index or the working tree,
+/// according to `git status`; or with `since`, the files changed between it and HEAD
+fn git_changes(dir: &Path, since: Option<&str>) -> Result<HashSet<PathBuf>, String> {
+    let git = |args: &[&str]| {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| format!("Could not run git ({})", e))?;
+        match output.status.success() {
+            true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+            false => Err(format!("git {} failed ({})", args[0], output.status)),
+        }
+    };
+    let root = PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim_end());
+
+    let mut changes = HashSet::new();
+    if let Some(since) = since {
+        let range = format!("{}...HEAD", since);
+        let diff = git(&["diff", "--name-only", "-z", &range, "--", "."])?;
+        for file in diff.split('\0').filter(|file| !file.is_empty()) {
+            changes.insert(root.join(file).canonicalize().unwrap_or(root.join(file)));
+        }
+        return Ok(changes);
+    }
+    let status = git(&["status", "--porcelain", "-z", "--untracked-files=all", "."])?;
+    let mut entries = status.split('\0');
+    while let Some(entry) = entries.next() {
+        // Paths are relative to the repository root, and renames are followed by the old one
+        let (state, file) = match (entry.get(..3), entry.get(3..)) {
+            (Some(state), Some(file)) if !file.is_empty() => (state, file),
+            _ => continue,
+        };
+        changes.insert(root.join(file).canonicalize().unwrap_or(root.join(file)));
+        if state.starts_with(['R', 'C']) {
+            entries.next();
+        }
+    }
+    Ok(changes)
+}
+
+/// Whether the output of `path` exists and was changed since it was built: its hash no longer
+/// matches the cached one, or without one, it is newer than the source and its includes
+fn edited_output(path: &Path, output: &Path, times: &Times, opts: &Options) -> bool {
+    let output = &match opts.gzip_only {
+        true => gzip::path(output),
+        false => output.to_path_buf(),
+    };
+    if !output.exists() {
+        return false;
+    }
+    let entry = path
+        .canonicalize()
+        .ok()
+        .and_then(|path| times.get(path.to_str()?).cloned());
+    match entry {
+        Some(entry) if !entry.output_hash.is_empty() => file_hash(output) != entry.output_hash,
+        _ => mtime(output) > newest_input(path).0,
+    }
+}
+
+/// Sorts `files` by modification time, newest first; files that no longer exist, like a deleted
+/// one --retry-failed still lists, go last
+fn sort_newest_first(files: &mut [PathBuf]) {
+    files.sort_by_key(|file| {
+        let modified = file.metadata().and_then(|metadata| metadata.modified());
+        std::cmp::Reverse(modified.ok())
+    });
+}
+
+/// Newest modification time among `path` and the files it includes, and the include it
+/// belongs to when that isn't `path` itself
+fn newest_input(path: &Path) -> (DateTime<Utc>, Option<PathBuf>) {
+    let mut newest = (mtime(path), None);
+    for file in deps::transitive(path) {
+        let modified = mtime(&file);
+        if modified > newest.0 {
+            newest = (modified, Some(file));
+        }
+    }
+    newest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cas_key_tells_output_options_apart() {
+        let args = vec!["--standalone".to_owned()];
+        let key = |opts: &Options| cas_key("source=1 args=2 template=3", "html", &args, opts);
+        let plain = key(&Options::default());
+        assert_eq!(plain, key(&Options::default()));
+
+        let variants = [
+            Options {
+                skip_preamble: true,
+                ..Options::default()
+            },
+            Options {
+                normalize_output: true,
+                ..Options::default()
+            },
+            Options {
+                embed_fingerprint: true,
+                ..Options::default()
+            },
+            Options {
+                strip_lines: Some(2),
+                ..Options::default()
+            },
+        ];
+        let mut keys = variants.iter().map(key).collect::<Vec<_>>();
+        keys.push(plain);
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), variants.len() + 1);
+    }
+
+    #[test]
+    fn prepended_preamble_ends_its_own_line() {
+        let dir = std::env::temp_dir().join(format!("compilador_banco-join-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let (preamble, source) = (dir.join("preamble.tex"), dir.join("a.tex"));
+        write(&source, "\\section{A}\n").unwrap();
+        let prepared = |opts: &Options| {
+            String::from_utf8(prepared_source(&source, opts).unwrap().unwrap()).unwrap()
+        };
+        let opts = Options {
+            prepend: Some(preamble.clone()),
+            ..Options::default()
+        };
+
+        // Without a trailing newline, `%` would comment out the source's first line
+        write(&preamble, "\\usepackage{amsmath} %").unwrap();
+        assert_eq!(prepared(&opts), "\\usepackage{amsmath} %\n\\section{A}\n");
+        write(&preamble, "\\usepackage{amsmath}\n").unwrap();
+        assert_eq!(prepared(&opts), "\\usepackage{amsmath}\n\\section{A}\n");
+
+        let opts = Options {
+            join_sep: Some("\\clearpage".to_owned()),
+            ..opts
+        };
+        write(&preamble, "\\usepackage{amsmath}").unwrap();
+        assert_eq!(
+            prepared(&opts),
+            "\\usepackage{amsmath}\n\\clearpage\n\\section{A}\n"
+        );
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_files_sort_after_the_newest() {
+        let dir = std::env::temp_dir().join(format!("compilador_banco-recent-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let (old, new, gone) = (
+            dir.join("old.tex"),
+            dir.join("new.tex"),
+            dir.join("gone.tex"),
+        );
+        for file in [&old, &new] {
+            write(file, "Text\n").unwrap();
+        }
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+
+        let mut files = vec![gone.clone(), old.clone(), new.clone()];
+        sort_newest_first(&mut files);
+        assert_eq!(files, [new, old, gone]);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edits_after_the_recorded_time_are_stale() {
+        let dir = std::env::temp_dir().join(format!("compilador_banco-edited-{}", process::id()));
+        create_dir_all(&dir).unwrap();
+        let (source, output) = (dir.join("a.tex"), dir.join("a.html"));
+        let set_modified = |ago: u64| {
+            File::options()
+                .write(true)
+                .open(&source)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(ago))
+                .unwrap();
+        };
+        write(&source, "First\n").unwrap();
+        set_modified(3600);
+        write(&output, "<p>First</p>\n").unwrap();
+
+        // Recorded as the compile loop does: the source's own time, sampled before pandoc ran
+        let mut times = Times::default();
+        times.insert(
+            cache_key(&source),
+            Entry {
+                modified: newest_input(&source).0,
+                fingerprint: "shared".to_owned(),
+                output_hash: file_hash(&output),
+                source_hash: file_hash(&source),
+                built: Some(Utc::now()),
+            },
+        );
+        let stale = || {
+            let opts = Options::default();
+            staleness(&source, &dir, &output, &times, "shared", None, false, &opts).0
+        };
+        assert!(!stale());
+
+        // Saved while pandoc was running, before the entry was written
+        write(&source, "Second\n").unwrap();
+        set_modified(1800);
+        assert!(stale());
+        remove_dir_all(&dir).unwrap();
+    }
 }
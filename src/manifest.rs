@@ -0,0 +1,45 @@
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+/// Sources to compile, in order, from a `--manifest` file: one path relative to the input
+/// directory per line, optionally followed by `-> FORMAT` to override `--to` for that file
+pub struct Manifest {
+    pub entries: Vec<(PathBuf, Option<String>)>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let contents = read_to_string(path).map_err(|e| e.to_string())?;
+        let mut entries = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (file, format) = match line.rsplit_once("->") {
+                Some((file, format)) => match format.trim() {
+                    "" => return Err(format!("line {}: missing format after ->", number + 1)),
+                    format => (file.trim(), Some(format.to_owned())),
+                },
+                None => (line, None),
+            };
+            if file.is_empty() {
+                return Err(format!("line {}: missing source", number + 1));
+            }
+            entries.push((PathBuf::from(file.trim_start_matches("./")), format));
+        }
+
+        Ok(Manifest { entries })
+    }
+
+    /// Entries whose source doesn't exist under `dir`
+    pub fn missing(&self, dir: &Path) -> Vec<&Path> {
+        self.entries
+            .iter()
+            .map(|(file, _)| file.as_path())
+            .filter(|file| !dir.join(file).is_file())
+            .collect()
+    }
+}
@@ -0,0 +1,76 @@
+use std::env::temp_dir;
+use std::fs::{read_to_string, write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::json;
+
+/// Tells apart the temporary files written at the same time by --threads-io workers
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Extensions of the metadata sidecars looked up next to a source, in order
+const EXTENSIONS: [&str; 4] = ["yaml", "yml", "json", "toml"];
+
+/// The `problem.meta.yaml` (or `.yml`, `.json`, `.toml`) next to `problem.tex`, if there's one
+pub fn sidecar(source: &Path) -> Option<PathBuf> {
+    let stem = source.file_stem()?.to_str()?;
+    EXTENSIONS
+        .iter()
+        .map(|ext| source.with_file_name(format!("{}.meta.{}", stem, ext)))
+        .find(|file| file.is_file())
+}
+
+/// A file pandoc's `--metadata-file` understands with the contents of `sidecar`, and whether
+/// it's a temporary one to remove afterwards; TOML, which pandoc doesn't read, is converted
+pub fn pandoc_file(sidecar: &Path) -> Result<(PathBuf, bool), String> {
+    if sidecar.extension().is_some_and(|ext| ext != "toml") {
+        return Ok((sidecar.to_path_buf(), false));
+    }
+
+    let file = temp_dir().join(format!(
+        "compilador_banco-{}-{}.json",
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    write(&file, to_json(sidecar)?).map_err(|e| e.to_string())?;
+    Ok((file, true))
+}
+
+/// Contents of `sidecar` as JSON; YAML goes through pandoc's `$meta-json$` template variable
+pub fn to_json(sidecar: &Path) -> Result<String, String> {
+    let contents = read_to_string(sidecar).map_err(|e| e.to_string())?;
+    match sidecar.extension().and_then(|ext| ext.to_str()) {
+        Some("json") => Ok(contents.trim().to_owned()),
+        Some("toml") => {
+            let value: toml::Value = toml::from_str(&contents).map_err(|e| e.to_string())?;
+            Ok(json::from_toml(&value))
+        }
+        _ => {
+            let template = temp_dir().join(format!(
+                "compilador_banco-{}-{}.meta",
+                process::id(),
+                TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+            ));
+            write(&template, "$meta-json$\n").map_err(|e| e.to_string())?;
+            let output = Command::new("pandoc")
+                .args(["-f", "markdown", "-t", "plain"])
+                .arg(format!(
+                    "--metadata-file={}",
+                    sidecar.to_str().unwrap_or_default()
+                ))
+                .arg(format!(
+                    "--template={}",
+                    template.to_str().unwrap_or_default()
+                ))
+                .stdin(Stdio::null())
+                .output();
+            let _ = std::fs::remove_file(&template);
+            let output = output.map_err(|e| format!("Could not run pandoc ({})", e))?;
+            if !output.status.success() {
+                return Err(format!("pandoc failed ({})", output.status));
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+        }
+    }
+}
@@ -0,0 +1,1337 @@
+use std::collections::HashMap;
+use std::env::var;
+use std::fs::read_to_string;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use regex::Regex;
+
+use crate::config::{Config, Rule};
+use crate::encoding::Encoding;
+use crate::glob::Glob;
+use crate::hash::Algorithm;
+use crate::theme::THEMES;
+
+/// pandoc input format of the source extensions known out of the box
+const DEFAULT_FORMATS: [(&str, &str); 3] = [("tex", "latex"), ("md", "markdown"), ("rst", "rst")];
+
+/// pandoc math renderers, and whether each accepts an asset URL
+const MATH_RENDERERS: [(&str, bool); 5] = [
+    ("katex", true),
+    ("mathjax", true),
+    ("webtex", true),
+    ("mathml", false),
+    ("gladtex", false),
+];
+
+pub const USAGE: &str = "\
+Usage: compilador_banco [OPTIONS] [DIR | FILE | PATTERN | ARCHIVE | -] [-- PANDOC_ARGS...]
+
+Compiles every .tex file under DIR (default: current directory) to HTML
+inside DIR/html, skipping files that did not change since the last run.
+A single FILE is compiled into the html directory next to it, and a glob
+PATTERN (e.g. 'chapters/**/*.tex') compiles the files it matches into the
+html directory of their deepest common directory. An ARCHIVE (.tar.gz, .tgz,
+.tar or .zip) is extracted to a temporary directory and compiled into the
+html directory next to it.
+
+A `pandoc_args = [...]` list in a FILE.cb next to a source adds pandoc
+arguments for that file alone, and NAME.meta.yaml (or .yml, .json, .toml)
+next to NAME.tex is passed to pandoc as its metadata. A defaults.FORMAT.yaml
+in DIR (e.g. defaults.html.yaml) is passed as --defaults to the compilations
+to FORMAT.
+
+pandoc gets the `pandoc_args` of compilador_banco.toml first, then those in
+the CB_PANDOC_ARGS environment variable (split like a shell would), then the
+--pandoc-arg flags, then the FILE.cb ones and finally everything after `--`
+on the command line, as it is; for flags pandoc only takes once, the last one
+wins, so e.g. `-- --mathjax` overrides the math renderer.
+
+Options:
+  --manifest <FILE>     Only compile the sources listed in FILE, one path relative to DIR per
+                        line, in that order; `intro.tex -> pdf` overrides --to for a file
+  --rename-map <FILE>   Rename outputs using the `regex => replacement` lines in FILE
+  --filter-cmd <CMD>    Pipe each source through the shell command CMD before pandoc
+  --strip-lines <N>     Drop the first N lines of each source before passing it to pandoc
+  --skip-preamble       Drop everything before \\begin{document} from each source
+  --prepend <FILE>      Pass pandoc FILE followed by each source (or stdin), on its stdin;
+                        FILE comes after --filter-cmd, --strip-lines and --skip-preamble
+                        have processed the source, so a shared preamble isn't stripped
+  --join-sep <TEXT>     Put TEXT on a line of its own between the --prepend file and the
+                        source, e.g. '\\clearpage'; they're always split by a newline
+  --bibliography <FILE> Resolve citations against FILE with pandoc --citeproc; editing it
+                        only rebuilds the documents that cite something
+  --lua-filter <FILE>   Run pandoc with the Lua filter FILE (repeatable)
+  --include-before <FILE>
+                        Insert FILE at the start of every document's body (repeatable)
+  --include-after <FILE>
+                        Insert FILE at the end of every document's body (repeatable)
+  --epub-cover <IMAGE>  Use IMAGE as the cover of EPUB outputs
+  --epub-metadata <FILE>
+                        Pass FILE to pandoc as the Dublin Core metadata of EPUB outputs
+  --data-dir <DIR>      Have pandoc look for templates, filters and other resources in DIR
+  --ext <EXT>           Also compile files with extension EXT, e.g. md or rst (repeatable)
+  --exact-ext-case      Match source extensions case-sensitively, so a.TEX isn't a source
+  --format-map <E=FMT>  Read files with extension E as pandoc format FMT (repeatable)
+  --math <RENDERER>     Render math with katex (default), mathjax, webtex, mathml or gladtex;
+                        without it, only HTML outputs get a math renderer
+  --extract-media <DIR> Have pandoc extract embedded images into DIR inside the html directory
+  --math-url <URL>      Load the math renderer's assets from URL instead of its CDN
+  --pandoc-arg <ARG>    Pass ARG to every pandoc invocation (repeatable); $VAR and ${VAR}
+                        are replaced by environment variables
+  --allow-undefined-env Replace undefined variables in pandoc arguments with nothing
+                        instead of failing
+  -t, --to <FORMAT>     pandoc output format (default: html); pdf lets pandoc pick the writer
+  --list-formats        Print the output formats the installed pandoc supports and exit
+  --pdf-engine <NAME>   Program pandoc renders PDF outputs with, e.g. xelatex, lualatex or
+                        wkhtmltopdf (default: pdflatex)
+  --name-by <file|title>
+                        Name outputs after their source file (default) or its \\title{}
+  --split-by section    Compile each \\section{} of an HTML output to a page of its own, named
+                        after the section, and make the output a contents page linking them
+  --continue-numbering  Number sections (pandoc --number-sections) continuing from the sources
+                        before each one (by name, or in --manifest order), with --number-offset
+  --number-outputs      Prefix each output with its position among the sources (by name, or
+                        in --manifest order), padded to the width of the count, e.g. 001-intro.html
+  --default-lang <LANG> Write sources without a `%% lang: LANG` marker into html/LANG too
+  --index <FILE>        Write a JSON list of the compiled documents and their titles to FILE
+  --index-metadata      Add each source's metadata sidecar to the --index entries
+  --master-toc <FILE>   Write an HTML page to FILE with a nested list linking to every
+                        document and the headings of its output
+  --sitemap             Write html/sitemap.xml listing the generated pages
+  --sqlite <DB>         Also store each compiled document in the documents table (path, html,
+                        compiled_at) of the SQLite database DB, with the sqlite3 program; the
+                        output files are still written, as they tell which sources are stale
+  --validate-links      After the build, report the links of the HTML pages to local files
+                        that don't exist, by page
+  --dir-index           Write an index.html into each output directory listing its pages
+                        and subdirectories
+  --link-style <STYLE>  Link outputs in --index, --master-toc and --sitemap by their path relative to the
+                        html directory (relative, the default) or under --base-url (absolute)
+  --base-url <URL>      URL the html directory is published at
+  --profile <FILE>      Write how long each phase and file took to FILE (CSV, or JSON for .json)
+  --status-socket <PATH>
+                        Send a JSON line to the Unix socket (or named pipe) PATH as each file
+                        starts compiling and finishes, and when the run is done
+  --report <FILE>       Write a build report with the versions, arguments, the outcome and
+                        time of each file and the totals to FILE (Markdown, or JSON for .json)
+  --config <FILE>       Read settings from FILE instead of DIR/compilador_banco.toml
+  --include <PATTERN>   Only compile the sources of DIR matching PATTERN (repeatable)
+  --exclude <PATTERN>   Don't compile the sources of DIR matching PATTERN (repeatable);
+                        --verbose tells which pattern dropped each source
+  --assume-unchanged <PATTERN>
+                        Treat the sources of DIR matching PATTERN as up to date whatever
+                        their modification time or contents (repeatable)
+  --copy-dir <PATH>     Copy the files in the directory (or matching the pattern) PATH of
+                        DIR into the html directory as they are (repeatable)
+  --workdir <DIR>       Resolve the input and other relative paths against DIR instead
+                        of the current directory
+  --keep-cwd            Run pandoc from the current directory instead of each source's one
+  --output-subdir <NAME>
+                        Write outputs into DIR/NAME instead of DIR/html
+  --output-template <T> Name each output after the template T, relative to the html
+                        directory, with {dir} (of the source), {name}, {ext}, {date}
+                        and {title} (slugified) replaced, e.g. `{dir}/{name}.{ext}`
+  --fail-on-empty       Exit with an error when no source files are found
+  --check-permissions   Check that the output and cache can be written before compiling
+  --keep-temp           Leave the directory an ARCHIVE was extracted to in place
+  --datestamp           Write outputs into html/YYYY-MM-DD instead of html
+  --max-age <AGE>       Recompile outputs older than AGE even if unchanged (e.g. 7d, 12h, 30m)
+  --max-depth <N>       Descend at most N directory levels looking for sources (1 = only DIR)
+  -j, --jobs, --threads-cpu <N|auto>
+                        Threads used to scan DIR for sources (default: auto, one per CPU
+                        the process may use, within its container's CPU quota)
+  --threads-io <N>      pandoc processes run at the same time (default: 1); they mostly
+                        wait on disk and pandoc itself, so N can exceed the CPU count.
+                        Independent of --threads-cpu
+  --watch               Keep running, recompiling documents when they or the files they
+                        \\input change
+  --watch-poll <INTERVAL>
+                        Like --watch, checking for changes every INTERVAL (default: 500ms).
+                        Changes are found by polling modification times, which works on
+                        network mounts too; shorter intervals react faster but stat every
+                        source and include more often
+  --watch-initial-skip  With --watch, don't compile anything until the first change, then
+                        compile what the cache says is stale
+  --debounce <MS>       With --watch, wait until no file has changed for MS milliseconds
+                        before recompiling, so bursts of saves are one rebuild (default:
+                        the poll interval)
+  --since-last-run      Only compile files changed since the last run without failures
+                        started (and those whose output is missing), ignoring each
+                        file's cache entry
+  --git                 Only compile files git reports as changed since HEAD (or untracked),
+                        and those whose output is missing, ignoring the cache
+  --changed-since <REF> Only compile files changed between REF and HEAD (git diff REF...HEAD)
+                        and those including them, ignoring the cache, e.g. for pull requests
+  --shard <I/N>         Only compile the I-th of N groups the sorted sources are dealt into,
+                        to spread a bank over N machines sharing the cache
+  --grep <REGEX>        Only consider sources whose contents match REGEX, e.g.
+                        'begin\\{theorem\\}'
+  --recent <N>          Only compile the N most recently modified files, ignoring the cache
+  --compile-order <ORDER>
+                        Compile files by name (default), mtime (newest first), size
+                        (smallest first) or manifest (the --manifest order, its default)
+  --retry-failed        Only compile the files that failed in the previous run
+  --only-missing        Only compile files whose output doesn't exist, ignoring the cache
+  --hash-algorithm <blake3|sha256|xxhash>
+                        Digest the cache fingerprints inputs and outputs with (default:
+                        xxhash); switching recompiles everything once
+  --wait-for-lock <SECS>
+                        When another run over DIR is going on, wait up to SECS for it to
+                        finish instead of failing right away
+  --fail-fast           Stop at the first file that fails to compile
+  --ok-exit-codes <LIST>
+                        Comma separated pandoc exit codes that count as success besides 0
+  --timeout <SECS>      Stop pandoc runs that take longer than SECS and count them as failed;
+                        a source can ask for its own with a `%% cb: timeout=SECS` line
+  --max-timeout <SECS>  Longest timeout a source's `%% cb: timeout=SECS` line gets
+  --mem-limit <SIZE>    Cap the address space of each pandoc run at SIZE (e.g. 512M, 2G),
+                        with prlimit; runs past it fail
+  --cpu-limit <SECS>    Kill pandoc runs that use more than SECS seconds of CPU time and count
+                        them as failed, with prlimit
+  --protect-newer-output
+                        Don't overwrite outputs edited by hand since they were built (or
+                        newer than their source, without a cached hash); warn and skip them
+  --max-warnings <N>    Fail the run when pandoc prints more than N warnings in total
+  --strict              Count files skipped by --protect-newer-output as failures
+  --skip-identical-output
+                        Leave outputs (and their mtimes) untouched when pandoc produces
+                        the same bytes again
+  --embed-fingerprint   End each HTML output with a comment hashing its sources, pandoc
+                        arguments and templates, which decides staleness when the cache
+                        has no entry for it or the sources' modification times changed
+  --cas-dir <DIR>       Keep each output in DIR under a hash of its sources, pandoc
+                        arguments, templates and pandoc version, and copy it from there
+                        instead of running pandoc when another build already made it
+  --normalize-output    Strip a leading BOM from HTML outputs and end them with one newline
+  --output-encoding <CHARSET>
+                        Transcode HTML outputs from UTF-8 to CHARSET (iso-8859-1,
+                        windows-1252 or us-ascii) and set their <meta charset>; outputs
+                        with characters CHARSET lacks are left in UTF-8, with a warning
+  --also-text           Also write a plain text .txt next to each HTML output, e.g. for a
+                        search index
+  --gzip                Also write a gzip-compressed .html.gz next to each output
+  --gzip-only           Like --gzip, but only keep the .html.gz
+  --preserve-times      Give each output the modification time of its source
+  --chmod <MODE>        Give each written output the octal permissions MODE, e.g. 644
+                        (Unix only)
+  --chmod-dirs <MODE>   Give the directories holding written outputs the octal permissions
+                        MODE, e.g. 755 (Unix only)
+  --stdout              Write the HTML of a single FILE (or - for stdin) to stdout
+  --preview-one <FILE>  Compile FILE to a standalone page in the temporary directory and open
+                        it in the default browser, leaving the cache and outputs alone
+  --on-file <CMD>       Run the shell command CMD after writing each output, with CB_SOURCE
+                        and CB_OUTPUT set to the source and output paths (the .gz one
+                        under --gzip-only)
+  --deploy <TARGET>     After a run without failures, rsync the html directory to TARGET,
+                        e.g. user@host:/var/www/bank
+  --deploy-dry-run      With --deploy, only show what rsync would transfer
+  --stamp <FILE>        After a run without failures (or more than --max-warnings), update
+                        the modification time of FILE, creating it if needed, for make
+  --print-outputs       Finish by printing the path of every file written in this run
+  --outputs-format <text|json|null>
+                        How --print-outputs lists the paths: one per line (default), as a
+                        JSON array, or NUL-terminated for xargs -0; implies --print-outputs
+  --prune               Remove HTML files whose source no longer exists
+  --clean               Remove the html directory and the cache, then exit
+  --export-cache <FILE> Write the cache to FILE, with paths relative to DIR, and exit
+  --import-cache <FILE> Merge a cache written by --export-cache into DIR's, and exit
+  --overwrite           With --import-cache, replace the cache instead of merging
+  --graph <FILE>        Write the sources and the files they include as a Graphviz DOT
+                        graph to FILE, and exit
+  --init                Create DIR with an example source, configuration and .gitignore,
+                        and exit
+  --snapshot <NAME>     Save a copy of the cache as NAME, and exit
+  --restore <NAME>      Replace the cache with the copy saved as NAME, and exit
+  --verify-cache        Check each cache entry against its source and output, and exit
+  --diff-against <DIR>  Compile every source into a temporary directory instead of the html
+                        one, without the cache, list which outputs are changed, unchanged,
+                        new or removed compared to those in DIR, and exit
+  --touch               Record every source in the cache as compiled as it is now, without
+                        running pandoc, and exit
+  -y, --yes             Don't ask for confirmation before --prune or --clean delete files
+  --estimate            Print how long compiling the stale files should take and exit
+  --estimate-size       Print roughly how much space the outputs of the stale files take,
+                        warning if the output volume hasn't that much free, and exit
+  --print-config        Print the settings in effect, after reading the configuration file, and exit
+  --stats               Print section, item and math counts of the sources and exit
+  --lint                Check the sources for unbalanced braces, \\begin without \\end and
+                        unclosed math $ without running pandoc, and exit
+  --summary-only        Print nothing but a final PASS or FAIL line with the counts
+  --compact             Print a single line per file, leaving pandoc's warnings to the
+                        summary at the end
+  --progress-json       Write a JSON line to stderr as each file starts, with its index,
+                        the total, its path and the percentage done, and one at the end
+  --quiet-pandoc        Don't show what pandoc prints to stderr unless it fails
+  --quiet-skips         Don't print a line for each file skipped as up to date
+  -v, --verbose         Explain why each file is compiled or skipped
+  --color <WHEN>        Color output: auto (default, only on terminals without NO_COLOR),
+                        always or never
+  --theme <THEME>       Colors of the status labels: default, high-contrast (no red/green)
+                        or mono (bold only)
+  -h, --help            Print this help and exit
+
+Exit status:
+  0  Everything compiled (or was up to date)
+  1  Invalid options, input or configuration
+  2  pandoc (or the PDF engine) was not found
+  3  A file or directory couldn't be read or written
+  4  One or more files failed to compile, --max-warnings was exceeded or --lint found
+     problems
+  5  --verify-cache found entries that don't match the files, or --diff-against found
+     outputs that differ
+";
+
+#[derive(Debug, Default, Clone)]
+pub struct Options {
+    pub input: Option<String>,
+    pub rename_map: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    /// Output formats the manifest gives its entries, by path relative to DIR
+    pub manifest_formats: HashMap<String, String>,
+    /// Sources the manifest lists, relative to DIR, in its order
+    pub manifest_sources: Vec<PathBuf>,
+    /// Directory outputs are written under (the html directory, above any --datestamp one),
+    /// which discovery doesn't descend into
+    pub output_dir: Option<PathBuf>,
+    pub index: Option<PathBuf>,
+    pub index_metadata: bool,
+    pub master_toc: Option<PathBuf>,
+    pub profile: Option<PathBuf>,
+    pub report: Option<PathBuf>,
+    pub stamp: Option<PathBuf>,
+    pub status_socket: Option<PathBuf>,
+    pub sqlite: Option<PathBuf>,
+    pub validate_links: bool,
+    pub sitemap: bool,
+    pub dir_index: bool,
+    pub absolute_links: bool,
+    pub base_url: Option<String>,
+    pub filter_cmd: Option<String>,
+    pub strip_lines: Option<usize>,
+    pub skip_preamble: bool,
+    pub prepend: Option<PathBuf>,
+    pub join_sep: Option<String>,
+    pub lua_filters: Vec<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub bibliography: Option<PathBuf>,
+    pub epub_cover: Option<PathBuf>,
+    pub epub_metadata: Option<PathBuf>,
+    pub include_before: Vec<PathBuf>,
+    pub include_after: Vec<PathBuf>,
+    pub extensions: Vec<String>,
+    pub format_map: Vec<(String, String)>,
+    pub math: Option<String>,
+    pub math_url: Option<String>,
+    pub extract_media: Option<PathBuf>,
+    pub pandoc_args: Vec<String>,
+    /// Everything after `--`, passed to pandoc after every other argument
+    pub passthrough_args: Vec<String>,
+    /// How long a pandoc run may take, in seconds, unless the source says otherwise
+    pub timeout: Option<u64>,
+    /// Cap on the timeouts sources ask for, in seconds
+    pub max_timeout: Option<u64>,
+    /// Address space limit for pandoc, in bytes
+    pub mem_limit: Option<u64>,
+    /// CPU time limit for pandoc, in seconds
+    pub cpu_limit: Option<u64>,
+    /// From `CB_PANDOC_ARGS`, until [`Options::apply_config`] puts them in `pandoc_args`
+    pub env_pandoc_args: Vec<String>,
+    pub allow_undefined_env: bool,
+    pub to: Option<String>,
+    pub pdf_engine: Option<String>,
+    pub name_by_title: bool,
+    pub number_outputs: bool,
+    pub continue_numbering: bool,
+    pub split_sections: bool,
+    pub default_lang: Option<String>,
+    pub config: Option<PathBuf>,
+    /// Per-directory settings from the configuration file
+    pub rules: Vec<(Glob, Rule)>,
+    /// Paths or patterns, relative to DIR, of the files to copy into the output untouched
+    pub copy_dirs: Vec<Glob>,
+    /// --include and --exclude patterns, as written and parsed
+    pub include: Vec<(String, Glob)>,
+    pub exclude: Vec<(String, Glob)>,
+    pub assume_unchanged: Vec<(String, Glob)>,
+    pub workdir: Option<PathBuf>,
+    pub keep_cwd: bool,
+    pub output_subdir: Option<String>,
+    pub output_template: Option<String>,
+    pub keep_temp: bool,
+    pub check_permissions: bool,
+    pub fail_on_empty: bool,
+    pub datestamp: bool,
+    pub max_depth: Option<usize>,
+    pub exact_ext_case: bool,
+    pub max_age: Option<Duration>,
+    /// `None` means one per CPU
+    pub jobs: Option<usize>,
+    pub threads_io: usize,
+    pub watch: bool,
+    pub watch_poll: Option<Duration>,
+    pub watch_initial_skip: bool,
+    pub debounce: Option<Duration>,
+    pub git: bool,
+    pub changed_since: Option<String>,
+    /// --shard I/N, as (I, N) with I counted from 1
+    pub shard: Option<(usize, usize)>,
+    pub since_last_run: bool,
+    pub grep: Option<Regex>,
+    /// --recent N
+    pub recent: Option<usize>,
+    pub compile_order: Option<String>,
+    pub retry_failed: bool,
+    pub only_missing: bool,
+    pub fail_fast: bool,
+    pub wait_for_lock: Option<Duration>,
+    pub hash_algorithm: Algorithm,
+    pub ok_exit_codes: Vec<i32>,
+    pub protect_newer_output: bool,
+    pub strict: bool,
+    pub max_warnings: Option<usize>,
+    pub skip_identical_output: bool,
+    pub normalize_output: bool,
+    /// None leaves outputs in the UTF-8 pandoc writes
+    pub output_encoding: Option<Encoding>,
+    pub embed_fingerprint: bool,
+    pub cas_dir: Option<PathBuf>,
+    pub also_text: bool,
+    pub gzip: bool,
+    pub gzip_only: bool,
+    pub preserve_times: bool,
+    pub chmod: Option<u32>,
+    pub chmod_dirs: Option<u32>,
+    pub stdout: bool,
+    pub preview_one: bool,
+    pub on_file: Option<String>,
+    pub deploy: Option<String>,
+    pub deploy_dry_run: bool,
+    pub print_outputs: bool,
+    pub outputs_format: Option<String>,
+    pub prune: bool,
+    pub clean: bool,
+    pub export_cache: Option<PathBuf>,
+    pub verify_cache: bool,
+    pub touch: bool,
+    pub init: bool,
+    pub snapshot: Option<String>,
+    pub restore: Option<String>,
+    pub graph: Option<PathBuf>,
+    pub import_cache: Option<PathBuf>,
+    pub overwrite: bool,
+    pub yes: bool,
+    pub estimate: bool,
+    pub estimate_size: bool,
+    pub print_config: bool,
+    pub diff_against: Option<PathBuf>,
+    pub list_formats: bool,
+    pub stats: bool,
+    pub lint: bool,
+    pub summary_only: bool,
+    pub quiet_pandoc: bool,
+    pub progress_json: bool,
+    pub quiet_skips: bool,
+    pub compact: bool,
+    pub verbose: bool,
+    pub color: Option<String>,
+    pub theme: Option<String>,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut opts = Options {
+            threads_io: 1,
+            ..Options::default()
+        };
+
+        while let Some(arg) = args.next() {
+            // Accept both `--option value` and `--option=value`
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => {
+                    (name.to_owned(), Some(value.to_owned()))
+                }
+                _ => (arg.clone(), None),
+            };
+            let mut value = || -> Result<String, String> {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or(format!("Missing value for {}", name))
+            };
+
+            match name.as_str() {
+                "--rename-map" => opts.rename_map = Some(PathBuf::from(value()?)),
+                "--manifest" => opts.manifest = Some(PathBuf::from(value()?)),
+                "--filter-cmd" => opts.filter_cmd = Some(value()?),
+                "--lua-filter" => opts.lua_filters.push(PathBuf::from(value()?)),
+                "--include-before" => opts.include_before.push(PathBuf::from(value()?)),
+                "--include-after" => opts.include_after.push(PathBuf::from(value()?)),
+                "--bibliography" => opts.bibliography = Some(PathBuf::from(value()?)),
+                "--epub-cover" => opts.epub_cover = Some(PathBuf::from(value()?)),
+                "--epub-metadata" => opts.epub_metadata = Some(PathBuf::from(value()?)),
+                "--data-dir" => opts.data_dir = Some(PathBuf::from(value()?)),
+                "--exact-ext-case" => opts.exact_ext_case = true,
+                "--ext" => opts
+                    .extensions
+                    .push(value()?.trim_start_matches('.').to_owned()),
+                "--format-map" => {
+                    let mapping = value()?;
+                    let (ext, format) = mapping.split_once('=').ok_or(format!(
+                        "Invalid --format-map {} (expected EXT=FORMAT)",
+                        mapping
+                    ))?;
+                    opts.format_map
+                        .push((ext.trim_start_matches('.').to_owned(), format.to_owned()));
+                }
+                "--math" => opts.math = Some(value()?),
+                "--extract-media" => opts.extract_media = Some(PathBuf::from(value()?)),
+                "--math-url" => opts.math_url = Some(value()?),
+                "--pandoc-arg" => opts.pandoc_args.push(value()?),
+                "--allow-undefined-env" => opts.allow_undefined_env = true,
+                "-t" | "--to" => opts.to = Some(value()?),
+                "--pdf-engine" => opts.pdf_engine = Some(value()?),
+                "--number-outputs" => opts.number_outputs = true,
+                "--continue-numbering" => opts.continue_numbering = true,
+                "--split-by" => match value()?.as_str() {
+                    "section" => opts.split_sections = true,
+                    other => {
+                        return Err(format!("Invalid --split-by {} (expected section)", other))
+                    }
+                },
+                "--name-by" => {
+                    opts.name_by_title = match value()?.as_str() {
+                        "file" => false,
+                        "title" => true,
+                        other => {
+                            return Err(format!(
+                                "Invalid --name-by {} (expected file or title)",
+                                other
+                            ))
+                        }
+                    }
+                }
+                "--default-lang" => opts.default_lang = Some(value()?),
+                "--index" => opts.index = Some(PathBuf::from(value()?)),
+                "--index-metadata" => opts.index_metadata = true,
+                "--master-toc" => opts.master_toc = Some(PathBuf::from(value()?)),
+                "--sitemap" => opts.sitemap = true,
+                "--dir-index" => opts.dir_index = true,
+                "--link-style" => {
+                    opts.absolute_links = match value()?.as_str() {
+                        "relative" => false,
+                        "absolute" => true,
+                        style => {
+                            return Err(format!(
+                                "Invalid --link-style {} (expected relative or absolute)",
+                                style
+                            ))
+                        }
+                    }
+                }
+                "--base-url" => opts.base_url = Some(value()?),
+                "--profile" => opts.profile = Some(PathBuf::from(value()?)),
+                "--report" => opts.report = Some(PathBuf::from(value()?)),
+                "--stamp" => opts.stamp = Some(PathBuf::from(value()?)),
+                "--status-socket" => opts.status_socket = Some(PathBuf::from(value()?)),
+                "--validate-links" => opts.validate_links = true,
+                "--sqlite" => opts.sqlite = Some(PathBuf::from(value()?)),
+                "--config" => opts.config = Some(PathBuf::from(value()?)),
+                "--copy-dir" => opts.copy_dirs.push(Glob::new(&value()?)?),
+                "--include" => {
+                    let pattern = value()?;
+                    opts.include.push((pattern.clone(), Glob::new(&pattern)?));
+                }
+                "--exclude" => {
+                    let pattern = value()?;
+                    opts.exclude.push((pattern.clone(), Glob::new(&pattern)?));
+                }
+                "--assume-unchanged" => {
+                    let pattern = value()?;
+                    opts.assume_unchanged
+                        .push((pattern.clone(), Glob::new(&pattern)?));
+                }
+                "--workdir" => opts.workdir = Some(PathBuf::from(value()?)),
+                "--keep-cwd" => opts.keep_cwd = true,
+                "--fail-on-empty" => opts.fail_on_empty = true,
+                "--check-permissions" => opts.check_permissions = true,
+                "--output-template" => {
+                    let template = value()?;
+                    if let Some(unknown) = unknown_placeholder(&template) {
+                        return Err(format!(
+                            "Unknown placeholder {} in --output-template (expected {})",
+                            unknown,
+                            PLACEHOLDERS.join(", ")
+                        ));
+                    }
+                    if template.split(['/', '\\']).any(|part| part == "..") {
+                        return Err(format!(
+                            "Invalid --output-template {} (outputs can't leave the output directory)",
+                            template
+                        ));
+                    }
+                    opts.output_template = Some(template);
+                }
+                "--keep-temp" => opts.keep_temp = true,
+                "--datestamp" => opts.datestamp = true,
+                "--output-subdir" => {
+                    let name = value()?;
+                    let path = Path::new(&name);
+                    if name.is_empty()
+                        || path.is_absolute()
+                        || path.components().any(|c| c == Component::ParentDir)
+                    {
+                        return Err(format!(
+                            "Invalid --output-subdir {} (expected a path inside DIR)",
+                            name
+                        ));
+                    }
+                    opts.output_subdir = Some(name);
+                }
+                "--max-age" => opts.max_age = Some(parse_duration(&value()?)?),
+                "--strip-lines" => {
+                    let lines = value()?;
+                    opts.strip_lines = match lines.parse() {
+                        Ok(lines) => Some(lines),
+                        Err(_) => {
+                            return Err(format!("Invalid --strip-lines {} (expected N)", lines))
+                        }
+                    }
+                }
+                "--prepend" => opts.prepend = Some(PathBuf::from(value()?)),
+                "--join-sep" => opts.join_sep = Some(value()?),
+                "--skip-preamble" => opts.skip_preamble = true,
+                "--max-depth" => {
+                    let depth = value()?;
+                    opts.max_depth = match depth.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(format!("Invalid --max-depth {} (expected N >= 1)", depth))
+                        }
+                        Ok(depth) => Some(depth),
+                    }
+                }
+                "--threads-io" => {
+                    let threads = value()?;
+                    opts.threads_io = match threads.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(format!(
+                                "Invalid --threads-io {} (expected N >= 1)",
+                                threads
+                            ))
+                        }
+                        Ok(threads) => threads,
+                    }
+                }
+                "-j" | "--jobs" | "--threads-cpu" => {
+                    let jobs = value()?;
+                    opts.jobs = match jobs.as_str() {
+                        "auto" => None,
+                        _ => match jobs.parse() {
+                            Ok(0) | Err(_) => {
+                                return Err(format!(
+                                    "Invalid --jobs {} (expected N >= 1 or auto)",
+                                    jobs
+                                ))
+                            }
+                            Ok(jobs) => Some(jobs),
+                        },
+                    }
+                }
+                "--watch" => opts.watch = true,
+                "--watch-initial-skip" => opts.watch_initial_skip = true,
+                "--watch-poll" => {
+                    opts.watch = true;
+                    opts.watch_poll = Some(parse_duration(&value()?)?);
+                }
+                "--debounce" => {
+                    let ms = value()?;
+                    match ms.parse() {
+                        Ok(ms) => opts.debounce = Some(Duration::from_millis(ms)),
+                        Err(_) => return Err(format!("Invalid --debounce {} (expected MS)", ms)),
+                    }
+                }
+                "--git" => opts.git = true,
+                "--changed-since" => opts.changed_since = Some(value()?),
+                "--shard" => {
+                    let shard = value()?;
+                    let parsed = shard
+                        .split_once('/')
+                        .and_then(|(i, n)| Some((i.parse().ok()?, n.parse().ok()?)));
+                    opts.shard = match parsed {
+                        Some((i, n)) if i >= 1 && i <= n => Some((i, n)),
+                        _ => {
+                            return Err(format!(
+                                "Invalid --shard {} (expected I/N with 1 <= I <= N)",
+                                shard
+                            ))
+                        }
+                    };
+                }
+                "--since-last-run" => opts.since_last_run = true,
+                "--grep" => {
+                    let pattern = value()?;
+                    match Regex::new(&pattern) {
+                        Ok(regex) => opts.grep = Some(regex),
+                        Err(e) => return Err(format!("Invalid --grep {} ({})", pattern, e)),
+                    }
+                }
+                "--compile-order" => {
+                    let order = value()?;
+                    if !["name", "mtime", "size", "manifest"].contains(&order.as_str()) {
+                        return Err(format!(
+                            "Invalid --compile-order {} (expected name, mtime, size or manifest)",
+                            order
+                        ));
+                    }
+                    opts.compile_order = Some(order);
+                }
+                "--recent" => {
+                    let recent = value()?;
+                    opts.recent = match recent.parse() {
+                        Ok(0) | Err(_) => {
+                            return Err(format!("Invalid --recent {} (expected N >= 1)", recent))
+                        }
+                        Ok(recent) => Some(recent),
+                    }
+                }
+                "--retry-failed" => opts.retry_failed = true,
+                "--only-missing" => opts.only_missing = true,
+                "--fail-fast" => opts.fail_fast = true,
+                "--hash-algorithm" => {
+                    let name = value()?;
+                    opts.hash_algorithm = Algorithm::parse(&name).ok_or_else(|| {
+                        format!(
+                            "Invalid --hash-algorithm {} (expected blake3, sha256 or xxhash)",
+                            name
+                        )
+                    })?;
+                }
+                "--wait-for-lock" => {
+                    let secs = value()?;
+                    match secs.parse() {
+                        Ok(secs) => opts.wait_for_lock = Some(Duration::from_secs(secs)),
+                        Err(_) => {
+                            return Err(format!("Invalid --wait-for-lock {} (expected SECS)", secs))
+                        }
+                    }
+                }
+                "--ok-exit-codes" => {
+                    let codes = value()?;
+                    for code in codes.split(',').map(str::trim) {
+                        match code.parse() {
+                            Ok(code) => opts.ok_exit_codes.push(code),
+                            Err(_) => {
+                                return Err(format!(
+                                    "Invalid --ok-exit-codes {} (expected a list like 3,64)",
+                                    codes
+                                ))
+                            }
+                        }
+                    }
+                }
+                "--timeout" | "--max-timeout" => {
+                    let secs = value()?;
+                    let secs = match secs.parse() {
+                        Ok(secs) if secs > 0 => secs,
+                        _ => return Err(format!("Invalid {} {} (expected SECS)", name, secs)),
+                    };
+                    match name.as_str() {
+                        "--timeout" => opts.timeout = Some(secs),
+                        _ => opts.max_timeout = Some(secs),
+                    }
+                }
+                "--mem-limit" => opts.mem_limit = Some(parse_size(&value()?)?),
+                "--cpu-limit" => {
+                    let secs = value()?;
+                    match secs.parse() {
+                        Ok(secs) if secs > 0 => opts.cpu_limit = Some(secs),
+                        _ => return Err(format!("Invalid --cpu-limit {} (expected SECS)", secs)),
+                    }
+                }
+                "--protect-newer-output" => opts.protect_newer_output = true,
+                "--strict" => opts.strict = true,
+                "--max-warnings" => {
+                    let max = value()?;
+                    match max.parse() {
+                        Ok(max) => opts.max_warnings = Some(max),
+                        Err(_) => {
+                            return Err(format!("Invalid --max-warnings {} (expected N)", max))
+                        }
+                    }
+                }
+                "--skip-identical-output" => opts.skip_identical_output = true,
+                "--normalize-output" => opts.normalize_output = true,
+                "--output-encoding" => {
+                    let name = value()?;
+                    let encoding = Encoding::parse(&name).ok_or_else(|| {
+                        format!(
+                            "Invalid --output-encoding {} (expected utf-8, iso-8859-1, \
+                             windows-1252 or us-ascii)",
+                            name
+                        )
+                    })?;
+                    opts.output_encoding = Some(encoding).filter(|e| *e != Encoding::Utf8);
+                }
+                "--embed-fingerprint" => opts.embed_fingerprint = true,
+                "--cas-dir" => opts.cas_dir = Some(PathBuf::from(value()?)),
+                "--also-text" => opts.also_text = true,
+                "--gzip" => opts.gzip = true,
+                "--gzip-only" => opts.gzip_only = true,
+                "--preserve-times" => opts.preserve_times = true,
+                "--chmod" | "--chmod-dirs" => {
+                    let mode = value()?;
+                    let parsed = match u32::from_str_radix(&mode, 8) {
+                        Ok(parsed) if parsed <= 0o7777 => parsed,
+                        _ => {
+                            return Err(format!(
+                                "Invalid {} {} (expected an octal mode like 644)",
+                                name, mode
+                            ))
+                        }
+                    };
+                    match name.as_str() {
+                        "--chmod" => opts.chmod = Some(parsed),
+                        _ => opts.chmod_dirs = Some(parsed),
+                    }
+                }
+                "--stdout" => opts.stdout = true,
+                "--preview-one" => {
+                    if opts.input.is_some() {
+                        return Err("--preview-one takes the place of the input".to_owned());
+                    }
+                    opts.preview_one = true;
+                    opts.input = Some(value()?);
+                }
+                "--deploy" => opts.deploy = Some(value()?),
+                "--deploy-dry-run" => opts.deploy_dry_run = true,
+                "--on-file" => opts.on_file = Some(value()?),
+                "--print-outputs" => opts.print_outputs = true,
+                "--outputs-format" => {
+                    let format = value()?;
+                    if !["text", "json", "null"].contains(&format.as_str()) {
+                        return Err(format!(
+                            "Invalid --outputs-format {} (expected text, json or null)",
+                            format
+                        ));
+                    }
+                    opts.print_outputs = true;
+                    opts.outputs_format = Some(format);
+                }
+                "--prune" => opts.prune = true,
+                "--clean" => opts.clean = true,
+                "--graph" => opts.graph = Some(PathBuf::from(value()?)),
+                "--snapshot" => opts.snapshot = Some(snapshot_name(value()?)?),
+                "--restore" => opts.restore = Some(snapshot_name(value()?)?),
+                "--init" => opts.init = true,
+                "--verify-cache" => opts.verify_cache = true,
+                "--diff-against" => opts.diff_against = Some(PathBuf::from(value()?)),
+                "--touch" => opts.touch = true,
+                "--export-cache" => opts.export_cache = Some(PathBuf::from(value()?)),
+                "--import-cache" => opts.import_cache = Some(PathBuf::from(value()?)),
+                "--overwrite" => opts.overwrite = true,
+                "-y" | "--yes" => opts.yes = true,
+                "--estimate" => opts.estimate = true,
+                "--estimate-size" => opts.estimate_size = true,
+                "--print-config" => opts.print_config = true,
+                "--list-formats" => opts.list_formats = true,
+                "--stats" => opts.stats = true,
+                "--lint" => opts.lint = true,
+                "--summary-only" => opts.summary_only = true,
+                "--compact" => opts.compact = true,
+                "--quiet-pandoc" => opts.quiet_pandoc = true,
+                "--progress-json" => opts.progress_json = true,
+                "--quiet-skips" => opts.quiet_skips = true,
+                "-v" | "--verbose" => opts.verbose = true,
+                "--color" => {
+                    let when = value()?;
+                    if !["auto", "always", "never"].contains(&when.as_str()) {
+                        return Err(format!(
+                            "Invalid --color {} (expected auto, always or never)",
+                            when
+                        ));
+                    }
+                    opts.color = Some(when);
+                }
+                "--theme" => {
+                    let theme = value()?;
+                    if !THEMES.contains(&theme.as_str()) {
+                        return Err(format!(
+                            "Invalid --theme {} (expected {})",
+                            theme,
+                            THEMES.join(", ")
+                        ));
+                    }
+                    opts.theme = Some(theme);
+                }
+                "-h" | "--help" => opts.help = true,
+                "--" => opts.passthrough_args.extend(args.by_ref()),
+                _ if name.starts_with('-') && name != "-" => {
+                    return Err(format!("Unknown option {}", name))
+                }
+                _ if opts.input.is_some() => {
+                    return Err(format!("Unexpected argument {}", name));
+                }
+                _ => opts.input = Some(arg),
+            }
+        }
+
+        if let Ok(args) = var("CB_PANDOC_ARGS") {
+            opts.env_pandoc_args =
+                split_args(&args).map_err(|e| format!("Invalid CB_PANDOC_ARGS ({})", e))?;
+        }
+        for arg in opts.pandoc_args.iter_mut() {
+            *arg = expand_env(arg, opts.allow_undefined_env)?;
+        }
+
+        if opts.git && opts.changed_since.is_some() {
+            return Err("--git can't be used with --changed-since".to_owned());
+        }
+
+        if opts.watch_initial_skip && !opts.watch {
+            return Err("--watch-initial-skip needs --watch".to_owned());
+        }
+
+        if opts.compile_order.as_deref() == Some("manifest") && opts.manifest.is_none() {
+            return Err("--compile-order manifest needs --manifest".to_owned());
+        }
+
+        if opts.join_sep.is_some() && opts.prepend.is_none() {
+            return Err("--join-sep needs --prepend".to_owned());
+        }
+
+        if opts.absolute_links && opts.base_url.is_none() {
+            return Err("--link-style absolute needs a --base-url".to_owned());
+        }
+
+        let math = opts.math();
+        match MATH_RENDERERS.iter().find(|(name, _)| *name == math) {
+            None => return Err(format!("Unknown math renderer {}", math)),
+            Some((_, false)) if opts.math_url.is_some() => {
+                return Err(format!("--math-url can't be used with --math {}", math));
+            }
+            _ => (),
+        }
+
+        Ok(opts)
+    }
+
+    /// Fills in what wasn't given on the command line from the configuration file
+    pub fn apply_config(&mut self, config: Config) -> Result<(), String> {
+        if self.to.is_none() {
+            self.to = config.to;
+        }
+
+        let mut pandoc_args = Vec::new();
+        for arg in &config.pandoc_args {
+            pandoc_args.push(expand_env(arg, self.allow_undefined_env)?);
+        }
+        pandoc_args.append(&mut self.env_pandoc_args);
+        pandoc_args.append(&mut self.pandoc_args);
+        self.pandoc_args = pandoc_args;
+
+        for rule in config.rules {
+            if let Some(math) = &rule.math {
+                if !MATH_RENDERERS.iter().any(|(name, _)| name == math) {
+                    return Err(format!(
+                        "Unknown math renderer {} in the rule for {}",
+                        math, rule.path
+                    ));
+                }
+            }
+            self.rules.push((Glob::new(&rule.path)?, rule));
+        }
+        Ok(())
+    }
+
+    /// Output format of the source at `relative` (to the input directory)
+    pub fn output_format(&self, relative: &Path) -> &str {
+        let relative = relative.to_str().unwrap_or_default().replace('\\', "/");
+        if let Some(format) = self.manifest_formats.get(&relative) {
+            return format;
+        }
+        self.rules
+            .iter()
+            .filter(|(glob, _)| glob.contains(&relative))
+            .find_map(|(_, rule)| rule.to.as_deref())
+            .unwrap_or_else(|| self.to.as_deref().unwrap_or("html"))
+    }
+
+    /// The --assume-unchanged pattern the source at `relative` matches, if any
+    pub fn assumed_unchanged(&self, relative: &Path) -> Option<&str> {
+        let relative = relative.to_str().unwrap_or_default().replace('\\', "/");
+        self.assume_unchanged
+            .iter()
+            .find(|(_, glob)| glob.contains(&relative))
+            .map(|(pattern, _)| pattern.as_str())
+    }
+
+    /// Math renderer a configuration rule picks for the source at `relative`, if any
+    pub fn rule_math(&self, relative: &Path) -> Option<&str> {
+        let relative = relative.to_str().unwrap_or_default().replace('\\', "/");
+        self.rules
+            .iter()
+            .filter(|(glob, _)| glob.contains(&relative))
+            .find_map(|(_, rule)| rule.math.as_deref())
+    }
+
+    /// Extensions of every output format in use
+    pub fn output_extensions(&self) -> Vec<&str> {
+        let mut formats = vec![self.to.as_deref().unwrap_or("html")];
+        formats.extend(self.rules.iter().filter_map(|(_, rule)| rule.to.as_deref()));
+        formats.extend(self.manifest_formats.values().map(String::as_str));
+        if self.also_text {
+            formats.push("plain");
+        }
+        formats.into_iter().map(extension).collect()
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs.unwrap_or_else(|| {
+            let cpus = std::thread::available_parallelism().map_or(1, |jobs| jobs.get());
+            cpu_quota().map_or(cpus, |quota| quota.min(cpus))
+        })
+    }
+
+    /// How an output at `relative` (to the html directory) is linked from generated files
+    pub fn link(&self, relative: &str) -> String {
+        match (&self.base_url, self.absolute_links) {
+            (Some(base_url), true) => format!("{}/{}", base_url.trim_end_matches('/'), relative),
+            _ => relative.to_owned(),
+        }
+    }
+
+    /// Whether pandoc runs under --mem-limit or --cpu-limit
+    pub fn limits_pandoc(&self) -> bool {
+        self.mem_limit.is_some() || self.cpu_limit.is_some()
+    }
+
+    pub fn math(&self) -> &str {
+        self.math.as_deref().unwrap_or("katex")
+    }
+
+    /// pandoc flag selecting the math renderer
+    pub fn math_flag(&self) -> String {
+        match &self.math_url {
+            Some(url) => format!("--{}={}", self.math(), url),
+            None => format!("--{}", self.math()),
+        }
+    }
+
+    /// pandoc flag selecting the math renderer of the source at `relative`: a configuration
+    /// rule's, or the global one; only HTML outputs get the default renderer
+    pub fn file_math_flag(&self, relative: &Path) -> Option<String> {
+        if let Some(math) = self.rule_math(relative) {
+            return Some(format!("--{}", math));
+        }
+        let html = extension(self.output_format(relative)) == "html";
+        (html || self.math.is_some() || self.math_url.is_some()).then(|| self.math_flag())
+    }
+
+    /// How often --watch looks for changes
+    pub fn watch_poll(&self) -> Duration {
+        self.watch_poll.unwrap_or(Duration::from_millis(500))
+    }
+
+    /// How long --watch waits for edits to settle
+    pub fn debounce(&self) -> Duration {
+        self.debounce.unwrap_or_else(|| self.watch_poll())
+    }
+
+    /// Program pandoc renders PDF with
+    pub fn pdf_engine(&self) -> &str {
+        self.pdf_engine.as_deref().unwrap_or("pdflatex")
+    }
+
+    /// Whether any file may be compiled to PDF, by the global format or a configuration rule
+    pub fn wants_pdf(&self) -> bool {
+        self.to.as_deref() == Some("pdf")
+            || self
+                .rules
+                .iter()
+                .any(|(_, rule)| rule.to.as_deref() == Some("pdf"))
+    }
+
+    /// Whether `path` has one of the extensions that get compiled
+    pub fn is_source(&self, path: &Path) -> bool {
+        self.has_extension(path, "tex")
+            || self.extensions.iter().any(|e| self.has_extension(path, e))
+            || self
+                .format_map
+                .iter()
+                .any(|(e, _)| self.has_extension(path, e))
+    }
+
+    /// Whether `dir` is the [`output_dir`](Options::output_dir), so outputs like the .tex of
+    /// `--to latex` aren't taken for sources
+    pub fn is_output_dir(&self, dir: &Path) -> bool {
+        let output = match &self.output_dir {
+            Some(output) => output,
+            None => return false,
+        };
+        // Only resolved when the names match, so big trees aren't canonicalized throughout
+        dir == output
+            || (dir.file_name() == output.file_name()
+                && dir
+                    .canonicalize()
+                    .is_ok_and(|dir| output.canonicalize().ok() == Some(dir)))
+    }
+
+    /// Whether `path` ends in `.ext`, ignoring case unless --exact-ext-case
+    fn has_extension(&self, path: &Path, ext: &str) -> bool {
+        match (path.extension(), self.exact_ext_case) {
+            (Some(actual), true) => actual == ext,
+            (Some(actual), false) => actual.to_str().is_some_and(|a| a.eq_ignore_ascii_case(ext)),
+            (None, _) => false,
+        }
+    }
+
+    /// pandoc format `path` is read as, chosen by its extension
+    pub fn input_format(&self, path: &Path) -> &str {
+        self.format_map
+            .iter()
+            .rev()
+            .map(|(e, format)| (e.as_str(), format.as_str()))
+            .chain(DEFAULT_FORMATS.iter().copied())
+            .find(|(e, _)| self.has_extension(path, e))
+            .map_or("latex", |(_, format)| format)
+    }
+}
+
+/// CPUs the cgroup of this process may use, rounded up, when it has a quota. The standard library
+/// already looks at it, but only at the cgroup it can find from `/proc/self/cgroup`, which misses
+/// containers that mount their own cgroup at the root
+fn cpu_quota() -> Option<usize> {
+    let (quota, period) = match read_to_string("/sys/fs/cgroup/cpu.max") {
+        // cgroup v2: `max 100000` without a limit
+        Ok(max) => {
+            let (quota, period) = max.trim().split_once(' ')?;
+            (quota.parse::<u64>().ok()?, period.parse::<u64>().ok()?)
+        }
+        // cgroup v1: a quota of -1 without a limit
+        Err(_) => {
+            let read = |file| read_to_string(format!("/sys/fs/cgroup/cpu/{}", file)).ok();
+            let quota = read("cpu.cfs_quota_us")?.trim().parse::<u64>().ok()?;
+            let period = read("cpu.cfs_period_us")?.trim().parse::<u64>().ok()?;
+            (quota, period)
+        }
+    };
+    match period {
+        0 => None,
+        _ => Some(quota.div_ceil(period).max(1) as usize),
+    }
+}
+
+/// Splits `text` into words at unquoted whitespace, handling '...', "..." and \\ escapes
+fn split_args(text: &str) -> Result<Vec<String>, String> {
+    let (mut args, mut word, mut in_word) = (Vec::new(), String::new(), false);
+    let mut quote = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                word.extend(chars.next());
+                in_word = true;
+            }
+            (Some(_), c) => word.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_owned());
+    }
+    if in_word {
+        args.push(word);
+    }
+    Ok(args)
+}
+
+/// Snapshots are files in a directory of their own, so their names can't be paths
+fn snapshot_name(name: String) -> Result<String, String> {
+    match name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        true => Err(format!("Invalid snapshot name {}", name)),
+        false => Ok(name),
+    }
+}
+
+/// Placeholders `--output-template` replaces
+const PLACEHOLDERS: [&str; 5] = ["{dir}", "{name}", "{ext}", "{date}", "{title}"];
+
+/// First `{...}` in `template` that isn't one of [`PLACEHOLDERS`]
+fn unknown_placeholder(template: &str) -> Option<&str> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = start + rest[start..].find('}')? + 1;
+        let placeholder = &rest[start..end];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            return Some(placeholder);
+        }
+        rest = &rest[end..];
+    }
+    None
+}
+
+/// Parses a duration like `7d`, `12h`, `30m`, `45s`, `2w` or `500ms` (a bare number is seconds)
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        "w" => 7 * 24 * 60 * 60 * 1000,
+        _ => {
+            return Err(format!(
+                "Invalid duration {} (expected e.g. 7d, 12h, 30m)",
+                text
+            ))
+        }
+    };
+    match number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(millis))
+    {
+        Some(millis) => Ok(Duration::from_millis(millis)),
+        None => Err(format!(
+            "Invalid duration {} (expected e.g. 7d, 12h, 30m)",
+            text
+        )),
+    }
+}
+
+/// Parses a size like `512M`, `2G` or `64K` (a bare number is bytes)
+pub fn parse_size(text: &str) -> Result<u64, String> {
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let bytes = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1 << 10,
+        "M" | "MB" => 1 << 20,
+        "G" | "GB" => 1 << 30,
+        _ => return Err(format!("Invalid size {} (expected e.g. 512M, 2G)", text)),
+    };
+    match number.parse::<u64>() {
+        Ok(number) if number > 0 => number
+            .checked_mul(bytes)
+            .ok_or_else(|| format!("Invalid size {} (too large)", text)),
+        _ => Err(format!("Invalid size {} (expected e.g. 512M, 2G)", text)),
+    }
+}
+
+/// Replaces `$VAR` and `${VAR}` in `arg` with the value of the environment variable
+pub fn expand_env(arg: &str, allow_undefined: bool) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = arg;
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, len) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => return Err(format!("Unclosed ${{ in pandoc argument {}", arg)),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            }
+        };
+
+        if name.is_empty() {
+            // A lone `$` isn't a reference
+            expanded.push('$');
+        } else {
+            match var(name) {
+                Ok(value) => expanded.push_str(&value),
+                Err(_) if allow_undefined => (),
+                Err(_) => {
+                    return Err(format!(
+                        "Undefined environment variable {} in pandoc argument {}",
+                        name, arg
+                    ))
+                }
+            }
+        }
+        rest = &after[len..];
+    }
+
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Extension of the files pandoc writes for an output format
+pub fn extension(format: &str) -> &str {
+    match format {
+        "html" | "html4" | "html5" | "revealjs" | "slidy" | "slideous" | "s5" | "dzslides" => {
+            "html"
+        }
+        "epub" | "epub2" | "epub3" => "epub",
+        "latex" | "beamer" | "context" => "tex",
+        "markdown" | "gfm" | "commonmark" | "markdown_strict" => "md",
+        "plain" => "txt",
+        "jats" | "docbook" | "docbook4" | "docbook5" | "tei" => "xml",
+        format => format,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_extensions_ignore_case() {
+        let opts = Options {
+            extensions: vec!["md".to_owned()],
+            ..Options::default()
+        };
+        for name in ["a.tex", "a.TeX", "a.TEX", "b.md", "b.MD", "b.Md"] {
+            assert!(opts.is_source(Path::new(name)), "{}", name);
+        }
+        for name in ["a.txt", "a.rst", "tex", "a.tex.bak"] {
+            assert!(!opts.is_source(Path::new(name)), "{}", name);
+        }
+
+        let exact = Options {
+            exact_ext_case: true,
+            ..opts
+        };
+        assert!(exact.is_source(Path::new("a.tex")));
+        assert!(!exact.is_source(Path::new("a.TeX")));
+        assert!(!exact.is_source(Path::new("b.MD")));
+    }
+
+    #[test]
+    fn output_template_cant_leave_the_output_directory() {
+        let parse = |template: &str| {
+            Options::parse(vec!["--output-template".to_owned(), template.to_owned()].into_iter())
+        };
+        assert!(parse("{dir}/{name}.{ext}").is_ok());
+        assert!(parse("{dir}/..{name}.{ext}").is_ok());
+        assert!(parse("../{name}.{ext}").is_err());
+        assert!(parse("{dir}/../../{name}.{ext}").is_err());
+        assert!(parse("{dir}\\..\\{name}.{ext}").is_err());
+    }
+}
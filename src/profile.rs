@@ -0,0 +1,63 @@
+use std::fs::write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::json;
+
+/// Time spent in each phase of a run, and on each compiled file, for `--profile`
+#[derive(Debug, Default)]
+pub struct Profile {
+    rows: Vec<(&'static str, String, Duration)>,
+}
+
+impl Profile {
+    pub fn phase(&mut self, phase: &'static str, duration: Duration) {
+        self.rows.push((phase, String::new(), duration));
+    }
+
+    pub fn file(&mut self, phase: &'static str, file: &Path, duration: Duration) {
+        let file = file.to_string_lossy().replace('\\', "/");
+        self.rows.push((phase, file, duration));
+    }
+
+    /// Writes the rows as JSON when `path` ends in `.json`, CSV otherwise
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let contents = if path.extension().is_some_and(|ext| ext == "json") {
+            let rows = self
+                .rows
+                .iter()
+                .map(|(phase, file, duration)| {
+                    format!(
+                        "  {{ \"phase\": {}, \"file\": {}, \"seconds\": {:.6} }}",
+                        json::string(phase),
+                        match file.is_empty() {
+                            true => "null".to_owned(),
+                            false => json::string(file),
+                        },
+                        duration.as_secs_f64()
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("[\n{}\n]\n", rows.join(",\n"))
+        } else {
+            let mut csv = String::from("phase,file,seconds\n");
+            for (phase, file, duration) in &self.rows {
+                csv += &format!(
+                    "{},{},{:.6}\n",
+                    phase,
+                    csv_field(file),
+                    duration.as_secs_f64()
+                );
+            }
+            csv
+        };
+        write(path, contents)
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::latex;
+
+/// Ordered list of `regex => replacement` rules applied to output file stems
+pub struct RenameMap {
+    rules: Vec<(Regex, String)>,
+}
+
+impl RenameMap {
+    pub fn load(path: &Path) -> Result<RenameMap, String> {
+        let contents = read_to_string(path).map_err(|e| e.to_string())?;
+        let mut rules = Vec::new();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (from, to) = line.split_once("=>").ok_or(format!(
+                "line {}: expected `regex => replacement`",
+                number + 1
+            ))?;
+            let regex = Regex::new(from.trim())
+                .map_err(|e| format!("line {}: invalid regex ({})", number + 1, e))?;
+            rules.push((regex, to.trim().to_owned()));
+        }
+
+        Ok(RenameMap { rules })
+    }
+
+    /// Applies every matching rule, in order, to the given file stem
+    pub fn apply(&self, stem: &str) -> String {
+        let mut name = stem.to_owned();
+        for (regex, replacement) in &self.rules {
+            name = regex.replace_all(&name, replacement.as_str()).into_owned();
+        }
+        name
+    }
+
+    /// Patterns of the rules that don't match any of the given file stems, each checked against
+    /// the stems as the rules before it left them, so a rule renaming another's output counts
+    pub fn unused<'a, I: Iterator<Item = &'a str>>(&self, stems: I) -> Vec<&str> {
+        let mut used = vec![false; self.rules.len()];
+        for stem in stems {
+            let mut name = stem.to_owned();
+            for (i, (regex, replacement)) in self.rules.iter().enumerate() {
+                if regex.is_match(&name) {
+                    used[i] = true;
+                    name = regex.replace_all(&name, replacement.as_str()).into_owned();
+                }
+            }
+        }
+        self.rules
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !used)
+            .map(|((regex, _), _)| regex.as_str())
+            .collect()
+    }
+}
+
+/// Output stems for `--name-by title`: each source's slugified `\title{}`, with `-2`, `-3`...
+/// added when several sources in a directory share one, and for `--number-outputs`: the stem
+/// after the source's zero-padded position among all of them, in the order given
+pub struct OutputNames {
+    names: HashMap<PathBuf, String>,
+    /// Sources without a title, which keep their own stem
+    pub untitled: Vec<PathBuf>,
+}
+
+impl OutputNames {
+    pub fn new(sources: &[PathBuf], by_title: bool, numbered: bool) -> OutputNames {
+        // Wide enough for the last number, so the names sort like the sources
+        let width = sources.len().to_string().len();
+
+        let (mut names, mut untitled) = (HashMap::new(), Vec::new());
+        let mut taken: HashMap<(PathBuf, String), usize> = HashMap::new();
+        for (i, source) in sources.iter().enumerate() {
+            let title = match by_title {
+                true => read_to_string(source)
+                    .ok()
+                    .and_then(|contents| latex::title(&contents))
+                    .map(|title| latex::slugify(&title))
+                    .filter(|slug| !slug.is_empty()),
+                false => None,
+            };
+            let name = match title {
+                Some(name) => name,
+                None => {
+                    if by_title {
+                        untitled.push(source.clone());
+                    }
+                    let stem = source.file_stem().unwrap_or_default();
+                    stem.to_str().unwrap_or_default().to_owned()
+                }
+            };
+
+            let dir = source.parent().unwrap_or(Path::new("")).to_path_buf();
+            let count = taken.entry((dir, name.clone())).or_insert(0);
+            *count += 1;
+            let name = match *count {
+                1 => name,
+                n => format!("{}-{}", name, n),
+            };
+            let name = match numbered {
+                true => format!("{:0width$}-{}", i + 1, name, width = width),
+                false => name,
+            };
+            names.insert(source.clone(), name);
+        }
+
+        OutputNames { names, untitled }
+    }
+
+    pub fn get(&self, source: &Path) -> Option<&str> {
+        self.names.get(source).map(|name| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unused_follows_chained_rules() {
+        let rename = RenameMap {
+            rules: vec![
+                (Regex::new("^ch").unwrap(), "chapter".to_owned()),
+                (Regex::new("^chapter").unwrap(), "part".to_owned()),
+                (Regex::new("^appendix").unwrap(), "annex".to_owned()),
+            ],
+        };
+        assert_eq!(
+            rename.unused(vec!["ch1", "intro"].into_iter()),
+            vec!["^appendix"]
+        );
+        assert_eq!(rename.apply("ch1"), "part1");
+    }
+}
@@ -0,0 +1,150 @@
+use std::fs::write;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::json;
+use crate::status;
+
+/// Everything about a run worth keeping as a build artifact, for `--report`; each outcome is
+/// also streamed to `--status-socket` as it's recorded
+#[derive(Debug)]
+pub struct Report {
+    pandoc: Option<String>,
+    /// Command line arguments the run was started with
+    args: Vec<String>,
+    /// Source, outcome, compile time and error of each file
+    files: Vec<(String, &'static str, Option<Duration>, String)>,
+}
+
+impl Report {
+    pub fn new(pandoc: Option<String>, args: Vec<String>) -> Report {
+        Report {
+            pandoc,
+            args,
+            files: Vec::new(),
+        }
+    }
+
+    pub fn file(&mut self, file: &Path, outcome: &'static str, duration: Option<Duration>) {
+        status::file(outcome, file, duration, None);
+        let file = file.to_string_lossy().replace('\\', "/");
+        self.files.push((file, outcome, duration, String::new()));
+    }
+
+    pub fn failure(&mut self, file: &Path, duration: Duration, error: &str) {
+        status::file("failed", file, Some(duration), Some(error));
+        let file = file.to_string_lossy().replace('\\', "/");
+        self.files
+            .push((file, "failed", Some(duration), error.to_owned()));
+    }
+
+    /// How many files had each outcome, in the order they first appeared
+    fn totals(&self) -> Vec<(&'static str, usize)> {
+        let mut totals: Vec<(&'static str, usize)> = Vec::new();
+        for (_, outcome, _, _) in &self.files {
+            match totals.iter_mut().find(|(name, _)| name == outcome) {
+                Some((_, count)) => *count += 1,
+                None => totals.push((outcome, 1)),
+            }
+        }
+        totals
+    }
+
+    /// Writes the report as JSON when `path` ends in `.json`, Markdown otherwise
+    pub fn save(&self, path: &Path, elapsed: Duration) -> std::io::Result<()> {
+        let contents = if path.extension().is_some_and(|ext| ext == "json") {
+            self.json(elapsed)
+        } else {
+            self.markdown(elapsed)
+        };
+        write(path, contents)
+    }
+
+    fn json(&self, elapsed: Duration) -> String {
+        let files = self
+            .files
+            .iter()
+            .map(|(file, outcome, duration, error)| {
+                format!(
+                    "    {{ \"file\": {}, \"outcome\": {}, \"seconds\": {}, \"error\": {} }}",
+                    json::string(file),
+                    json::string(outcome),
+                    match duration {
+                        Some(duration) => format!("{:.6}", duration.as_secs_f64()),
+                        None => "null".to_owned(),
+                    },
+                    match error.is_empty() {
+                        true => "null".to_owned(),
+                        false => json::string(error),
+                    }
+                )
+            })
+            .collect::<Vec<_>>();
+        let totals = self
+            .totals()
+            .iter()
+            .map(|(outcome, count)| format!("{}: {}", json::string(outcome), count))
+            .collect::<Vec<_>>();
+        let args = self
+            .args
+            .iter()
+            .map(|arg| json::string(arg))
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"version\": {},\n  \"pandoc\": {},\n  \"args\": [{}],\n  \"files\": [\n{}\n  ],\n  \"totals\": {{{}}},\n  \"seconds\": {:.6}\n}}\n",
+            json::string(env!("CARGO_PKG_VERSION")),
+            match &self.pandoc {
+                Some(pandoc) => json::string(pandoc),
+                None => "null".to_owned(),
+            },
+            args.join(", "),
+            files.join(",\n"),
+            totals.join(", "),
+            elapsed.as_secs_f64()
+        )
+    }
+
+    fn markdown(&self, elapsed: Duration) -> String {
+        let mut markdown = format!(
+            "# Build report\n\n- compilador_banco {}\n- {}\n- Arguments: `{}`\n\n",
+            env!("CARGO_PKG_VERSION"),
+            self.pandoc
+                .as_deref()
+                .unwrap_or("pandoc of unknown version"),
+            self.args.join(" ")
+        );
+        markdown += "| File | Outcome | Seconds | Error |\n| --- | --- | --- | --- |\n";
+        for (file, outcome, duration, error) in &self.files {
+            markdown += &format!(
+                "| {} | {} | {} | {} |\n",
+                table_cell(file),
+                outcome,
+                duration.map_or(String::new(), |duration| format!(
+                    "{:.2}",
+                    duration.as_secs_f64()
+                )),
+                table_cell(error)
+            );
+        }
+        let totals = self
+            .totals()
+            .iter()
+            .map(|(outcome, count)| format!("{} {}", count, outcome))
+            .collect::<Vec<_>>();
+        markdown += &format!(
+            "\n{} files in {:.2}s: {}\n",
+            self.files.len(),
+            elapsed.as_secs_f64(),
+            match totals.is_empty() {
+                true => "nothing to do".to_owned(),
+                false => totals.join(", "),
+            }
+        );
+        markdown
+    }
+}
+
+/// Text that can't break out of its Markdown table cell
+fn table_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
@@ -0,0 +1,357 @@
+use std::fs::{create_dir_all, read, read_dir, read_to_string, write};
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::cache::mtime;
+use crate::discover::find_files;
+use crate::encoding::Encoding;
+use crate::json;
+use crate::latex;
+use crate::metadata;
+use crate::options::Options;
+use crate::theme;
+
+/// Writes `documents` as a JSON array of `{ "source", "output", "title" }` objects, with paths
+/// relative to the source and output directories (outputs follow --link-style), plus "metadata"
+/// with --index-metadata
+pub fn write_index(
+    index: &Path,
+    documents: &[(PathBuf, PathBuf)],
+    src_dir: &Path,
+    html_dir: &Path,
+    opts: &Options,
+) -> std::io::Result<()> {
+    let relative = |path: &Path, base: &Path| {
+        let path = path.strip_prefix(base).unwrap_or(path);
+        path.to_string_lossy().replace('\\', "/")
+    };
+    let entries = documents
+        .iter()
+        .map(|(source, output)| {
+            let title = match read_to_string(source).ok().and_then(|s| latex::title(&s)) {
+                Some(title) => json::string(&title),
+                None => "null".to_owned(),
+            };
+            let mut entry = format!(
+                "\"source\": {}, \"output\": {}, \"title\": {}",
+                json::string(&relative(source, src_dir)),
+                json::string(&opts.link(&relative(output, html_dir))),
+                title
+            );
+            if opts.index_metadata {
+                let metadata = metadata::sidecar(source).and_then(|sidecar| {
+                    metadata::to_json(&sidecar)
+                        .map_err(|e| {
+                            eprintln!(
+                                "{} Could not read metadata {} ({})",
+                                theme::warn(),
+                                sidecar.to_str().unwrap_or("UNNAMED"),
+                                e
+                            )
+                        })
+                        .ok()
+                });
+                entry += &format!(", \"metadata\": {}", metadata.as_deref().unwrap_or("null"));
+            }
+            format!("  {{ {} }}", entry)
+        })
+        .collect::<Vec<_>>();
+    if let Some(parent) = index.parent() {
+        create_dir_all(parent)?;
+    }
+    match entries.is_empty() {
+        true => write(index, "[]\n"),
+        false => write(index, format!("[\n{}\n]\n", entries.join(",\n"))),
+    }
+}
+
+/// `path` as reached from the directory `from`, going up with `..` where they part
+fn relative_path(from: &Path, path: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let path = path.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&path).take_while(|(a, b)| a == b).count();
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    relative.extend(&path[common..]);
+    relative
+}
+
+/// Writes an HTML page to `toc` with a list of the HTML pages of `documents` that exist (by the
+/// title of their source, linked in the --link-style), each with the headings of the page nested
+/// by level and linked by their ids. Returns whether it changed, as it's left alone otherwise
+pub fn write_master_toc(
+    toc: &Path,
+    documents: &[(PathBuf, PathBuf)],
+    html_dir: &Path,
+    opts: &Options,
+) -> std::io::Result<bool> {
+    let heading = Regex::new(r#"(?s)<h([1-6])\b[^>]*\sid="([^"]*)"[^>]*>(.*?)</h[1-6]>"#).unwrap();
+    let tag = Regex::new(r"<[^>]*>").unwrap();
+
+    let mut items = Vec::new();
+    for (source, output) in documents {
+        if output.extension().is_none_or(|ext| ext != "html") {
+            continue;
+        }
+        let html = match read_output(output, opts) {
+            Some(html) => html,
+            None => continue,
+        };
+        let relative = output.strip_prefix(html_dir).unwrap_or(output);
+        // Relative links start from wherever the contents page is, which needn't be html/
+        let href = match opts.absolute_links {
+            true => opts.link(&relative.to_string_lossy().replace('\\', "/")),
+            false => relative_path(toc.parent().unwrap_or(Path::new("")), output)
+                .to_string_lossy()
+                .replace('\\', "/"),
+        };
+        let title = read_to_string(source)
+            .ok()
+            .and_then(|source| latex::title(&source))
+            .map(|title| xml_escape(&title))
+            .unwrap_or_else(|| xml_escape(&relative.to_string_lossy()));
+
+        let mut item = format!("<li><a href=\"{}\">{}</a>", xml_escape(&href), title);
+        // Levels of the open lists, outermost first; headings above the first one's level
+        // count as that level, so the list never closes early
+        let mut levels: Vec<usize> = Vec::new();
+        for captures in heading.captures_iter(&html) {
+            let text = tag.replace_all(&captures[3], "");
+            let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            let level = captures[1].parse().unwrap_or(1);
+            match levels.last().copied() {
+                Some(last) if level <= last => {
+                    let level = level.max(levels[0]);
+                    while levels.last().is_some_and(|last| *last > level) {
+                        levels.pop();
+                        item += "</li>\n</ul>\n";
+                    }
+                    *levels.last_mut().unwrap() = level;
+                    item += "</li>\n";
+                }
+                _ => {
+                    levels.push(level);
+                    item += "\n<ul>\n";
+                }
+            }
+            item += &format!(
+                "<li><a href=\"{}#{}\">{}</a>",
+                xml_escape(&href),
+                &captures[2],
+                text
+            );
+        }
+        for _ in levels {
+            item += "</li>\n</ul>\n";
+        }
+        items.push(item + "</li>");
+    }
+    let contents = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Contents</title></head>\n\
+         <body>\n<h1>Contents</h1>\n<ul>\n{}\n</ul>\n</body>\n</html>\n",
+        items.join("\n")
+    );
+
+    if read_to_string(toc).ok().as_deref() == Some(contents.as_str()) {
+        return Ok(false);
+    }
+    if let Some(parent) = toc.parent() {
+        create_dir_all(parent)?;
+    }
+    write(toc, contents)?;
+    Ok(true)
+}
+
+/// Writes `sitemap.xml` into `html_dir`, listing the HTML pages of `documents` that exist (as
+/// links in the --link-style) with the modification date of their source
+pub fn write_sitemap(
+    documents: &[(PathBuf, PathBuf)],
+    html_dir: &Path,
+    opts: &Options,
+) -> std::io::Result<PathBuf> {
+    let mut contents = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n"
+        .to_owned();
+    for (source, output) in documents {
+        if output.extension().is_none_or(|ext| ext != "html") || !output.is_file() {
+            continue;
+        }
+        let relative = output.strip_prefix(html_dir).unwrap_or(output);
+        let loc = opts.link(&relative.to_string_lossy().replace('\\', "/"));
+        contents += &format!(
+            "  <url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+            xml_escape(&loc),
+            mtime(source).format("%Y-%m-%d")
+        );
+    }
+    contents += "</urlset>\n";
+
+    let sitemap = html_dir.join("sitemap.xml");
+    write(&sitemap, contents)?;
+    Ok(sitemap)
+}
+
+/// Writes an `index.html` into `dir` and each directory under it with HTML files, linking to
+/// them (by the title of their source for `documents`) and to the subdirectories with a listing
+/// of their own, and returns how many changed. Directories where a source compiles to
+/// `index.html` keep it.
+pub fn write_dir_indexes(dir: &Path, html_dir: &Path, documents: &[(PathBuf, PathBuf)]) -> usize {
+    let mut written = 0;
+    let (mut subdirs, mut pages) = (Vec::new(), Vec::new());
+    for item in read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|item| item.ok())
+    {
+        let path = item.path();
+        if path.is_dir() {
+            written += write_dir_indexes(&path, html_dir, documents);
+            if path.join("index.html").is_file() {
+                subdirs.push(path);
+            }
+        } else if path.extension().is_some_and(|ext| ext == "html")
+            && path.file_name().is_some_and(|name| name != "index.html")
+        {
+            pages.push(path);
+        }
+    }
+    let index = dir.join("index.html");
+    if documents.iter().any(|(_, output)| *output == index)
+        || (subdirs.is_empty() && pages.is_empty())
+    {
+        return written;
+    }
+    subdirs.sort();
+    pages.sort();
+
+    let name = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    };
+    let heading = match dir.strip_prefix(html_dir) {
+        Ok(relative) if relative != Path::new("") => relative.to_string_lossy().replace('\\', "/"),
+        _ => "Index".to_owned(),
+    };
+    let mut items = Vec::new();
+    for subdir in &subdirs {
+        let name = name(subdir);
+        items.push(format!(
+            "<li><a href=\"{0}/index.html\">{0}/</a></li>",
+            xml_escape(&name)
+        ));
+    }
+    for page in &pages {
+        let title = documents
+            .iter()
+            .find(|(_, output)| output == page)
+            .and_then(|(source, _)| read_to_string(source).ok())
+            .and_then(|source| latex::title(&source))
+            .unwrap_or_else(|| name(page));
+        items.push(format!(
+            "<li><a href=\"{}\">{}</a></li>",
+            xml_escape(&name(page)),
+            xml_escape(&title)
+        ));
+    }
+    let contents = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{0}</title></head>\n\
+         <body>\n<h1>{0}</h1>\n<ul>\n{1}\n</ul>\n</body>\n</html>\n",
+        xml_escape(&heading),
+        items.join("\n")
+    );
+
+    if read_to_string(&index).ok().as_deref() != Some(contents.as_str()) {
+        match write(&index, contents) {
+            Ok(()) => written += 1,
+            Err(e) => eprintln!(
+                "{} Could not write {} ({})",
+                theme::error(),
+                index.to_str().unwrap_or("UNNAMED"),
+                e
+            ),
+        }
+    }
+    written
+}
+
+/// Reports the local links of the HTML pages under `html_dir` whose target doesn't exist,
+/// grouped by page; links with a scheme, like http: or mailto:, are left alone
+pub fn validate_links(html_dir: &Path, cwd: &Path, opts: &Options) {
+    let href = Regex::new(r#"href\s*=\s*["']([^"']*)["']"#).unwrap();
+    let scheme = Regex::new(r"^[A-Za-z][A-Za-z0-9+.-]*:").unwrap();
+    let mut pages = find_files(html_dir)
+        .into_iter()
+        .filter(|file| file.extension().is_some_and(|ext| ext == "html"))
+        .collect::<Vec<_>>();
+    pages.sort();
+
+    let mut broken_total = 0;
+    for page in &pages {
+        let contents = match read_output(page, opts) {
+            Some(contents) => contents,
+            None => continue,
+        };
+        let dir = page.parent().unwrap_or(html_dir);
+        let mut broken = Vec::new();
+        for link in href.captures_iter(&contents).map(|c| c[1].to_owned()) {
+            let target = link.split(['#', '?']).next().unwrap_or_default();
+            if target.is_empty() || target.starts_with("//") || scheme.is_match(target) {
+                continue;
+            }
+            let file = match target.strip_prefix('/') {
+                Some(rooted) => html_dir.join(rooted),
+                None => dir.join(target),
+            };
+            let exists = match file.is_dir() {
+                true => file.join("index.html").is_file(),
+                false => file.is_file(),
+            };
+            if !exists && !broken.contains(&link) {
+                broken.push(link);
+            }
+        }
+        if !broken.is_empty() {
+            println!(
+                "{} ./{}: {} broken links: {}",
+                theme::warn(),
+                page.strip_prefix(cwd)
+                    .unwrap_or(page)
+                    .to_str()
+                    .unwrap_or("UNNAMED"),
+                broken.len(),
+                broken.join(", ")
+            );
+            broken_total += broken.len();
+        }
+    }
+    println!(
+        "{} Checked the links of {} pages, {} broken",
+        theme::info(),
+        pages.len(),
+        broken_total
+    );
+}
+
+pub fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// The text of `output`, which may be in the --output-encoding
+fn read_output(output: &Path, opts: &Options) -> Option<String> {
+    let contents = read(output).ok()?;
+    match String::from_utf8(contents) {
+        Ok(text) => Some(text),
+        Err(e) => {
+            let encoding = opts.output_encoding.unwrap_or(Encoding::Utf8);
+            Some(encoding.decode(e.as_bytes()))
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::fs::read;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use chrono::prelude::*;
+
+use crate::gzip;
+use crate::options::Options;
+
+/// Upserts the outputs of the `compiled` sources into the `documents` table of `db`, keyed by
+/// their path relative to `src_dir`, and drops the rows of the `deleted` ones, with `sqlite3`
+pub fn store(
+    db: &Path,
+    compiled: &[(PathBuf, PathBuf)],
+    deleted: &[String],
+    src_dir: &Path,
+    opts: &Options,
+) -> Result<(), String> {
+    if compiled.is_empty() && deleted.is_empty() && db.exists() {
+        return Ok(());
+    }
+    let root = src_dir.canonicalize().unwrap_or(src_dir.to_path_buf());
+    let key = |path: &Path| {
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        quote(&relative.to_string_lossy().replace('\\', "/"))
+    };
+    let mut script = "CREATE TABLE IF NOT EXISTS documents (path TEXT PRIMARY KEY, html TEXT \
+                      NOT NULL, compiled_at TEXT NOT NULL);\nBEGIN;\n"
+        .to_owned();
+    let compiled_at = quote(&Utc::now().to_rfc3339());
+    for (source, output) in compiled {
+        let source = source.canonicalize().unwrap_or(source.clone());
+        // readfile() spares quoting whole documents into the script; with --gzip-only only the
+        // compressed copy is left, so the HTML goes in as a hex literal instead
+        let html = match opts.gzip_only {
+            true => {
+                let gz = gzip::path(output);
+                let html = read(&gz)
+                    .ok()
+                    .and_then(|gz| gzip::decompress(&gz))
+                    .ok_or_else(|| format!("Could not read {}", gz.to_string_lossy()))?;
+                let hex = html
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>();
+                format!("X'{}'", hex)
+            }
+            false => format!("readfile({})", quote(output.to_str().unwrap_or_default())),
+        };
+        script += &format!(
+            "INSERT INTO documents VALUES ({}, CAST({} AS TEXT), {}) ON CONFLICT(path) DO \
+             UPDATE SET html = excluded.html, compiled_at = excluded.compiled_at;\n",
+            key(&source),
+            html,
+            compiled_at
+        );
+    }
+    for source in deleted {
+        script += &format!(
+            "DELETE FROM documents WHERE path = {};\n",
+            key(Path::new(source))
+        );
+    }
+    script += "COMMIT;\n";
+
+    let mut child = Command::new("sqlite3")
+        .arg("-bail")
+        .arg(db)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run sqlite3 ({})", e))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .map_err(|e| format!("Could not write to sqlite3 ({})", e))?;
+    let status = child.wait().map_err(|e| e.to_string())?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(format!("sqlite3 failed ({})", status)),
+    }
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::json;
+
+/// Where `--status-socket` events go, once connected
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Connects to the listening Unix socket at `path`, or opens the named pipe there
+pub fn connect(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let sink: Box<dyn Write + Send> = match std::os::unix::net::UnixStream::connect(path) {
+        Ok(stream) => Box::new(stream),
+        Err(_) => Box::new(File::options().write(true).open(path)?),
+    };
+    #[cfg(not(unix))]
+    let sink: Box<dyn Write + Send> = Box::new(File::options().write(true).open(path)?);
+    *SINK.lock().unwrap() = Some(sink);
+    Ok(())
+}
+
+/// Sends a `{"event": ...}` line about `file`, if anything is listening
+pub fn file(event: &str, file: &Path, duration: Option<Duration>, error: Option<&str>) {
+    let mut line = format!(
+        "{{\"event\": {}, \"file\": {}",
+        json::string(event),
+        json::string(&file.to_string_lossy().replace('\\', "/"))
+    );
+    if let Some(duration) = duration {
+        line += &format!(", \"seconds\": {:.6}", duration.as_secs_f64());
+    }
+    if let Some(error) = error {
+        line += &format!(", \"error\": {}", json::string(error));
+    }
+    send(line + "}");
+}
+
+/// Sends the `done` event closing a run
+pub fn done(compiled: usize, skipped: usize, failed: usize) {
+    send(format!(
+        "{{\"event\": \"done\", \"compiled\": {}, \"skipped\": {}, \"failed\": {}}}",
+        compiled, skipped, failed
+    ));
+}
+
+/// Writes a --progress-json line to stderr: `file` is the `index`-th (from 1) of `total`, or
+/// `None` once all `total` are done
+pub fn progress(index: usize, total: usize, file: Option<&Path>) {
+    // Files done before this one
+    let done = index - usize::from(file.is_some());
+    let percent = match total {
+        0 => 100.0,
+        total => done as f64 * 100.0 / total as f64,
+    };
+    let file = match file {
+        Some(file) => json::string(&file.to_string_lossy().replace('\\', "/")),
+        None => "null".to_owned(),
+    };
+    eprintln!(
+        "{{\"index\": {}, \"total\": {}, \"file\": {}, \"percent\": {:.1}}}",
+        index, total, file, percent
+    );
+}
+
+fn send(line: String) {
+    let mut sink = SINK.lock().unwrap();
+    if let Some(writer) = sink.as_mut() {
+        // A listener that went away shouldn't stop the build
+        if writeln!(writer, "{}", line)
+            .and_then(|_| writer.flush())
+            .is_err()
+        {
+            *sink = None;
+        }
+    }
+}
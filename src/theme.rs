@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use yansi::{Color, Paint};
+
+/// Palettes accepted by `--theme`
+pub const THEMES: [&str; 3] = ["default", "high-contrast", "mono"];
+
+/// Index into [`THEMES`] of the palette in use
+static THEME: AtomicU8 = AtomicU8::new(0);
+
+pub fn set(theme: &str) {
+    let index = THEMES.iter().position(|name| *name == theme).unwrap_or(0);
+    THEME.store(index as u8, Ordering::Relaxed);
+}
+
+pub fn info() -> Paint<&'static str> {
+    label("INFO", Color::Cyan, Color::Blue)
+}
+
+pub fn warn() -> Paint<&'static str> {
+    label("WARN", Color::Yellow, Color::Yellow)
+}
+
+pub fn error() -> Paint<&'static str> {
+    label("ERROR", Color::Red, Color::Magenta)
+}
+
+pub fn pass() -> Paint<&'static str> {
+    label("PASS", Color::Green, Color::Blue)
+}
+
+pub fn fail() -> Paint<&'static str> {
+    label("FAIL", Color::Red, Color::Magenta)
+}
+
+/// High contrast avoids telling red from green apart; mono keeps only the bold inverted label
+fn label(text: &'static str, default: Color, high_contrast: Color) -> Paint<&'static str> {
+    let paint = match THEME.load(Ordering::Relaxed) {
+        0 => Paint::new(text).fg(default),
+        1 => Paint::new(text).fg(high_contrast),
+        _ => Paint::new(text),
+    };
+    paint.invert().bold()
+}
@@ -0,0 +1,73 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::theme;
+
+/// `[WARNING]` lines pandoc printed, with the source it was compiling
+static WARNINGS: Mutex<Vec<(PathBuf, String)>> = Mutex::new(Vec::new());
+
+/// Recognizable starts of pandoc warnings, and what to call them in the summary
+const KINDS: [(&str, &str); 6] = [
+    ("Could not fetch resource", "missing images or resources"),
+    (
+        "Could not convert TeX math",
+        "math pandoc could not convert",
+    ),
+    ("Duplicate identifier", "duplicate identifiers"),
+    ("Citeproc", "citation problems"),
+    (
+        "This document format requires a nonempty <title>",
+        "missing titles",
+    ),
+    ("Skipped", "skipped LaTeX commands"),
+];
+
+/// Remembers the warnings in pandoc's `stderr` while compiling `source`
+pub fn record(source: &Path, stderr: &str) {
+    let mut warnings = WARNINGS.lock().unwrap();
+    for line in stderr.lines() {
+        if let Some(warning) = line.strip_prefix("[WARNING] ") {
+            warnings.push((source.to_path_buf(), warning.to_owned()));
+        }
+    }
+}
+
+/// The warnings recorded since the last call, which starts the next pass of --watch afresh
+pub fn take() -> Vec<(PathBuf, String)> {
+    std::mem::take(&mut *WARNINGS.lock().unwrap())
+}
+
+/// Prints how many files had each kind of the `warnings` (see [`take`]), with a few of them
+pub fn print_summary(cwd: &Path, warnings: Vec<(PathBuf, String)>) {
+    if warnings.is_empty() {
+        return;
+    }
+
+    let mut kinds: BTreeMap<&str, Vec<PathBuf>> = BTreeMap::new();
+    for (source, warning) in warnings {
+        let kind = KINDS
+            .iter()
+            .find(|(prefix, _)| warning.starts_with(prefix))
+            .map_or("other warnings", |(_, kind)| kind);
+        let files = kinds.entry(kind).or_default();
+        if !files.contains(&source) {
+            files.push(source);
+        }
+    }
+
+    println!("{} pandoc warnings:", theme::warn());
+    for (kind, files) in kinds {
+        let examples = files
+            .iter()
+            .take(3)
+            .map(|file| file.strip_prefix(cwd).unwrap_or(file).to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let more = match files.len() {
+            0..=3 => String::new(),
+            n => format!(" and {} more", n - 3),
+        };
+        println!("  {} files: {} ({}{})", files.len(), kind, examples, more);
+    }
+}
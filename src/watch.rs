@@ -0,0 +1,64 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::deps;
+
+/// Polls the documents `discover` returns, and everything they include, every `interval` until
+/// some of them change; then keeps polling until `debounce` passes without further edits, so
+/// saving many files at once (or one file on every keystroke) comes back as a single batch
+pub fn wait_for_changes(
+    discover: impl Fn() -> Vec<PathBuf>,
+    interval: Duration,
+    debounce: Duration,
+) -> BTreeSet<PathBuf> {
+    let mut before = snapshot(&discover());
+    let mut changed = BTreeSet::new();
+    let mut last_edit = Instant::now();
+    loop {
+        // Once something changed, only the time left until the edits count as settled
+        let wait = match changed.is_empty() {
+            true => interval,
+            false => interval.min(debounce.saturating_sub(last_edit.elapsed())),
+        };
+        sleep(wait);
+        let now = snapshot(&discover());
+        let edited = now
+            .iter()
+            .filter(|(file, modified)| before.get(*file) != Some(modified))
+            .map(|(file, _)| file.clone())
+            .chain(
+                before
+                    .keys()
+                    .filter(|file| !now.contains_key(*file))
+                    .cloned(),
+            )
+            .collect::<Vec<_>>();
+        if !edited.is_empty() {
+            last_edit = Instant::now();
+        } else if !changed.is_empty() && last_edit.elapsed() >= debounce {
+            return changed;
+        }
+        changed.extend(edited);
+        before = now;
+    }
+}
+
+/// Modification time of each document and of each file they include
+fn snapshot(documents: &[PathBuf]) -> HashMap<PathBuf, Option<SystemTime>> {
+    let mut files = documents.iter().cloned().collect::<BTreeSet<_>>();
+    for document in documents {
+        files.extend(deps::transitive(document));
+    }
+    files
+        .into_iter()
+        .map(|file| {
+            let modified = file
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok();
+            (file, modified)
+        })
+        .collect()
+}